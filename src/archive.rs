@@ -0,0 +1,432 @@
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, SystemTime};
+
+use chrono::{Local, NaiveDate, TimeZone};
+
+use crate::error::KlsError;
+use crate::files::{FilesEntry, FilesType};
+
+// Compressed tarballs are piped through the matching command, like `tar -a` does
+const DECOMPRESSORS: [(&[u8], &str); 4] = [
+    (b"\x1f\x8b", "gzip"),
+    (b"BZh", "bzip2"),
+    (b"\xfd7zXZ\0", "xz"),
+    (b"\x28\xb5\x2f\xfd", "zstd")
+];
+
+enum Format {
+    Tar,
+    CompressedTar(&'static str),
+    Zip
+}
+
+// Guesses the format from the first bytes. Compressed files only count as
+// tarballs when their name says so, a plain `.gz` is listed as a file.
+fn detect(path: &Path) -> Option<Format> {
+    let mut head = [0; 512];
+    let mut file = File::open(path).ok()?;
+    let len = file.read(&mut head).ok()?;
+    let head = &head[..len];
+
+    if head.starts_with(b"PK\x03\x04") || head.starts_with(b"PK\x05\x06") {
+        return Some(Format::Zip);
+    }
+    if head.len() == 512 && &head[257..262] == b"ustar" {
+        return Some(Format::Tar);
+    }
+
+    let name = path.file_name()?.to_string_lossy().to_lowercase();
+    let tarball = name.contains(".tar.") || [".tgz", ".tbz", ".tbz2", ".txz", ".tzst"].iter().any(|ext| name.ends_with(ext));
+    DECOMPRESSORS
+        .iter()
+        .find(|(magic, _)| tarball && head.starts_with(magic))
+        .map(|(_, command)| Format::CompressedTar(command))
+}
+
+pub fn is_archive(path: &Path) -> bool {
+    path.is_file() && detect(path).is_some()
+}
+
+// Members of a tar or zip archive as entries, named the way the archive stores them
+pub fn read(path: &Path) -> Result<Vec<FilesEntry>, Box<dyn Error>> {
+    match detect(path) {
+        Some(Format::Tar) => read_tar(path, File::open(path)?),
+        Some(Format::CompressedTar(command)) => {
+            let mut child = Command::new(command)
+                .arg("-dc")
+                .stdin(File::open(path)?)
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .map_err(|e| KlsError::E(format!("cannot run {}", command), e.into()))?;
+            let stdout = child.stdout.take().ok_or_else(|| KlsError::S(format!("no output from {}", command)))?;
+
+            // Waited on either way so no zombie is left behind
+            let result = read_tar(path, stdout);
+            let status = child.wait();
+            let entries = result?;
+            status?;
+            Ok(entries)
+        },
+        Some(Format::Zip) => read_zip(path, File::open(path)?),
+        None => Err(KlsError::S(String::from("not a tar or zip archive")).into())
+    }
+}
+
+// What a member header says about it. `kind` is the tar type flag, zip
+// members are mapped onto it.
+struct Member {
+    name: String,
+    kind: u8,
+    target: String,
+    mode: u16,
+    size: u64,
+    mtime: SystemTime,
+    owner: (u32, u32)
+}
+
+impl Member {
+    fn into_entry(self, archive: &Path) -> FilesEntry {
+        // Directories are stored with a trailing slash, which -F or -p would double
+        let name = self.name.strip_suffix('/').filter(|name| !name.is_empty()).unwrap_or(&self.name);
+        // Shown as given, but an absolute name or `..` mustn't lead out of the
+        // archive's path. Entries aren't probed on disk either way.
+        let inside: PathBuf = Path::new(name)
+            .components()
+            .filter(|component| matches!(component, Component::Normal(_)))
+            .collect();
+        let path = archive.join(inside);
+        let file_type = match self.kind {
+            b'2' => FilesType::Sym(path, PathBuf::from(self.target)),
+            b'3' => FilesType::Char(path),
+            b'4' => FilesType::Block(path),
+            b'5' => FilesType::Dir(path),
+            b'6' => FilesType::Fifo(path),
+            _ => FilesType::File(path)
+        };
+
+        let size = if self.kind == b'5' { 0 } else { self.size };
+        let mut entry = FilesEntry::new_virtual(file_type, self.mode, size, self.mtime, self.owner);
+        entry.label = Some(name.into());
+        entry
+    }
+}
+
+// Numeric tar fields are octal text, or big-endian base-256 when the high bit is set
+fn tar_number(field: &[u8]) -> u64 {
+    if field.first().is_some_and(|b| b & 0x80 != 0) {
+        return field[1..].iter().fold((field[0] & 0x7f) as u64, |n, b| (n << 8) | *b as u64);
+    }
+
+    let text = String::from_utf8_lossy(field);
+    u64::from_str_radix(text.trim_matches(|c: char| c == '\0' || c == ' '), 8).unwrap_or(0)
+}
+
+fn tar_string(field: &[u8]) -> String {
+    let end = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+fn invalid_tar() -> KlsError {
+    KlsError::S(String::from("invalid tar archive"))
+}
+
+// Data is padded to whole blocks, a size too large for that is a corrupt header
+fn padded(size: u64) -> Result<u64, KlsError> {
+    size.checked_next_multiple_of(512).ok_or_else(invalid_tar)
+}
+
+fn read_data(reader: &mut impl Read, size: u64) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut data = vec![];
+    reader.take(size).read_to_end(&mut data)?;
+    skip(reader, padded(size)? - size)?;
+    Ok(data)
+}
+
+fn skip(reader: &mut impl Read, size: u64) -> io::Result<()> {
+    io::copy(&mut reader.take(size), &mut io::sink())?;
+    Ok(())
+}
+
+// Walks the headers one after another, with GNU long names and the path,
+// link and size records of pax extended headers applied to the next member
+fn read_tar(archive: &Path, mut reader: impl Read) -> Result<Vec<FilesEntry>, Box<dyn Error>> {
+    let mut entries = vec![];
+    let (mut long_name, mut long_target, mut pax): (Option<String>, Option<String>, Vec<(String, String)>) = (None, None, vec![]);
+    let mut header = [0; 512];
+    loop {
+        if let Err(e) = reader.read_exact(&mut header) {
+            // Some writers leave out the two zero blocks at the end
+            if e.kind() == io::ErrorKind::UnexpectedEof {
+                break;
+            }
+            return Err(e.into());
+        }
+        if header.iter().all(|b| *b == 0) {
+            break;
+        }
+
+        let kind = header[156];
+        let mut size = tar_number(&header[124..136]);
+        match kind {
+            b'L' => {
+                long_name = Some(tar_string(&read_data(&mut reader, size)?));
+                continue;
+            },
+            b'K' => {
+                long_target = Some(tar_string(&read_data(&mut reader, size)?));
+                continue;
+            },
+            b'x' => {
+                pax = parse_pax(&read_data(&mut reader, size)?);
+                continue;
+            },
+            b'g' => {
+                read_data(&mut reader, size)?;
+                continue;
+            },
+            _ => ()
+        }
+
+        let pax_value = |key: &str| pax.iter().find(|(k, _)| k == key).map(|(_, v)| v.clone());
+        let name = pax_value("path").or(long_name.take()).unwrap_or_else(|| {
+            let name = tar_string(&header[0..100]);
+            let prefix = tar_string(&header[345..500]);
+            // Only POSIX ustar has a prefix, old GNU headers keep other fields there
+            if &header[257..263] == b"ustar\0" && !prefix.is_empty() {
+                format!("{}/{}", prefix, name)
+            } else {
+                name
+            }
+        });
+        let target = pax_value("linkpath").or(long_target.take()).unwrap_or_else(|| tar_string(&header[157..257]));
+        if let Some(pax_size) = pax_value("size").and_then(|size| size.parse().ok()) {
+            size = pax_size;
+        }
+        let mtime = pax_value("mtime")
+            .and_then(|mtime| mtime.split('.').next().and_then(|secs| secs.parse().ok()))
+            .unwrap_or_else(|| tar_number(&header[136..148]));
+        pax.clear();
+
+        // Only regular files carry data, a hard link has its size but not its content
+        let data_size = if matches!(kind, b'0' | b'\0' | b'7') { size } else { 0 };
+        skip(&mut reader, padded(data_size)?)?;
+
+        entries.push(Member {
+            name,
+            kind,
+            target,
+            mode: tar_number(&header[100..108]) as u16 & 0o7777,
+            size,
+            mtime: SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs(mtime)).ok_or_else(invalid_tar)?,
+            owner: (tar_number(&header[108..116]) as u32, tar_number(&header[116..124]) as u32)
+        }.into_entry(archive));
+    }
+
+    Ok(entries)
+}
+
+// Records are `LENGTH KEY=VALUE\n`, the length counting the whole record
+fn parse_pax(data: &[u8]) -> Vec<(String, String)> {
+    let mut records = vec![];
+    let mut rest = data;
+    while let Some(space) = rest.iter().position(|b| *b == b' ') {
+        let Some(len) = std::str::from_utf8(&rest[..space]).ok().and_then(|len| len.parse::<usize>().ok()) else {
+            break;
+        };
+        if len <= space || len > rest.len() {
+            break;
+        }
+
+        let record = String::from_utf8_lossy(&rest[space + 1..len]);
+        if let Some((key, value)) = record.trim_end_matches('\n').split_once('=') {
+            records.push((key.to_string(), value.to_string()));
+        }
+        rest = &rest[len..];
+    }
+    records
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+// MS-DOS timestamps are local time with two second precision
+fn dos_time(date: u16, time: u16) -> SystemTime {
+    NaiveDate::from_ymd_opt(1980 + (date >> 9) as i32, ((date >> 5) & 0xf) as u32, (date & 0x1f) as u32)
+        .and_then(|day| day.and_hms_opt((time >> 11) as u32, ((time >> 5) & 0x3f) as u32, ((time & 0x1f) * 2) as u32))
+        .and_then(|datetime| Local.from_local_datetime(&datetime).earliest())
+        .map_or(SystemTime::UNIX_EPOCH, SystemTime::from)
+}
+
+// Reads the central directory at the end of the file, which has everything but
+// symlink targets. Those are the content of stored members and read from there.
+fn read_zip(archive: &Path, mut file: File) -> Result<Vec<FilesEntry>, Box<dyn Error>> {
+    const END_SIZE: usize = 22;
+    let invalid = || KlsError::S(String::from("invalid zip archive"));
+
+    // Zip doesn't record owners, members get the archive's own
+    let meta = fs::metadata(archive)?;
+    #[cfg(unix)]
+    let owner = {
+        use std::os::unix::fs::MetadataExt;
+        (meta.uid(), meta.gid())
+    };
+    #[cfg(not(unix))]
+    let owner = (0, 0);
+
+    // The end record is followed by a comment of up to 64K
+    let len = meta.len();
+    let tail_len = len.min((END_SIZE + u16::MAX as usize) as u64);
+    file.seek(SeekFrom::Start(len - tail_len))?;
+    let mut tail = vec![];
+    file.read_to_end(&mut tail)?;
+    let end = (0..tail.len().saturating_sub(END_SIZE - 1))
+        .rev()
+        .find(|i| tail[*i..].starts_with(b"PK\x05\x06"))
+        .ok_or_else(invalid)?;
+    let end = &tail[end..];
+
+    let (count, dir_size, dir_offset) = (u16_at(end, 10), u32_at(end, 12), u32_at(end, 16));
+    if count == u16::MAX || dir_offset == u32::MAX {
+        return Err(KlsError::S(String::from("zip64 archives aren't supported")).into());
+    }
+
+    // Both come from the archive, so they're checked against its real size first
+    if dir_offset as u64 + dir_size as u64 > len {
+        return Err(invalid().into());
+    }
+    let mut dir = vec![0; dir_size as usize];
+    file.seek(SeekFrom::Start(dir_offset as u64))?;
+    file.read_exact(&mut dir)?;
+
+    let mut entries = vec![];
+    let mut offset = 0;
+    for _ in 0..count {
+        let record = dir.get(offset..offset + 46).filter(|record| record.starts_with(b"PK\x01\x02")).ok_or_else(invalid)?;
+        let (name_len, extra_len, comment_len) = (u16_at(record, 28) as usize, u16_at(record, 30) as usize, u16_at(record, 32) as usize);
+        let name = dir.get(offset + 46..offset + 46 + name_len).ok_or_else(invalid)?;
+        let name = String::from_utf8_lossy(name).into_owned();
+
+        // Archives made on unix keep the mode in the upper half of the external attributes
+        let from_unix = record[5] == 3;
+        let attributes = u32_at(record, 38);
+        let mode = if from_unix && attributes >> 16 != 0 {
+            attributes >> 16
+        } else if name.ends_with('/') {
+            0o040755
+        } else {
+            0o100644
+        };
+        let kind = match mode & 0o170000 {
+            0o040000 => b'5',
+            0o120000 => b'2',
+            _ => b'0'
+        };
+
+        let (method, compressed_size, size, local_offset) = (u16_at(record, 10), u32_at(record, 20), u32_at(record, 24), u32_at(record, 42));
+        let target = if kind == b'2' && method == 0 {
+            read_stored(&mut file, local_offset, compressed_size, len).unwrap_or_default()
+        } else {
+            String::new()
+        };
+
+        entries.push(Member {
+            name,
+            kind,
+            target,
+            mode: (mode & 0o7777) as u16,
+            size: size as u64,
+            mtime: dos_time(u16_at(record, 14), u16_at(record, 12)),
+            owner
+        }.into_entry(archive));
+        offset += 46 + name_len + extra_len + comment_len;
+    }
+
+    Ok(entries)
+}
+
+// The data of an uncompressed member, found after its local header. `len` is
+// the archive's size, which the member has to fit in.
+fn read_stored(file: &mut File, offset: u32, size: u32, len: u64) -> io::Result<String> {
+    if offset as u64 + size as u64 > len {
+        return Err(io::ErrorKind::InvalidData.into());
+    }
+
+    let mut header = [0; 30];
+    file.seek(SeekFrom::Start(offset as u64))?;
+    file.read_exact(&mut header)?;
+
+    let skip = u16_at(&header, 26) as i64 + u16_at(&header, 28) as i64;
+    file.seek(SeekFrom::Current(skip))?;
+    let mut data = vec![0; size as usize];
+    file.read_exact(&mut data)?;
+    Ok(String::from_utf8_lossy(&data).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // A ustar header for a regular file, its numeric fields as given
+    fn header(name: &str, size: &[u8], mtime: &[u8]) -> Vec<u8> {
+        let mut header = vec![0; 512];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[100..108].copy_from_slice(b"0000644\0");
+        header[124..124 + size.len()].copy_from_slice(size);
+        header[136..136 + mtime.len()].copy_from_slice(mtime);
+        header[156] = b'0';
+        header[257..263].copy_from_slice(b"ustar\0");
+        header
+    }
+
+    // Big-endian base-256 with the high bit set, all ones
+    const HUGE: [u8; 12] = [0xff; 12];
+
+    fn read(data: Vec<u8>) -> Result<Vec<FilesEntry>, Box<dyn Error>> {
+        read_tar(Path::new("/test.tar"), Cursor::new(data))
+    }
+
+    #[test]
+    fn reads_a_member() {
+        let mut data = header("file.txt", b"00000000005\0", b"00000000144\0");
+        data.extend(b"hello");
+        data.resize(1024 + 1024, 0);
+
+        let entries = read(data).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].name_lossy(), "file.txt");
+        assert_eq!(entries[0].size, 5);
+        assert_eq!(entries[0].mtime, SystemTime::UNIX_EPOCH + Duration::from_secs(100));
+    }
+
+    #[test]
+    fn corrupt_headers_are_errors() {
+        // An mtime past what SystemTime holds
+        assert!(read(header("late", b"0\0", &HUGE)).is_err());
+        // A size that can't be padded to a whole block
+        assert!(read(header("big", &HUGE, b"0\0")).is_err());
+
+        // The same through a pax record and a GNU long name
+        let pax = b"30 mtime=18446744073709551615\n";
+        let mut data = header("././@PaxHeader", format!("{:011o}\0", pax.len()).as_bytes(), b"0\0");
+        data[156] = b'x';
+        data.extend(pax);
+        data.resize(1024, 0);
+        data.extend(header("file", b"0\0", b"0\0"));
+        assert!(read(data).is_err());
+
+        let mut data = header("././@LongLink", &HUGE, b"0\0");
+        data[156] = b'L';
+        assert!(read(data).is_err());
+    }
+}
@@ -0,0 +1,60 @@
+// Capability names by bit, as in linux/capability.h
+const NAMES: [&str; 41] = [
+    "chown", "dac_override", "dac_read_search", "fowner", "fsetid", "kill", "setgid", "setuid",
+    "setpcap", "linux_immutable", "net_bind_service", "net_broadcast", "net_admin", "net_raw", "ipc_lock", "ipc_owner",
+    "sys_module", "sys_rawio", "sys_chroot", "sys_ptrace", "sys_pacct", "sys_admin", "sys_boot", "sys_nice",
+    "sys_resource", "sys_time", "sys_tty_config", "mknod", "lease", "audit_write", "audit_control", "setfcap",
+    "mac_override", "mac_admin", "syslog", "wake_alarm", "block_suspend", "audit_read", "perfmon", "bpf",
+    "checkpoint_restore"
+];
+
+const REVISION_MASK: u32 = 0xff000000;
+const FLAG_EFFECTIVE: u32 = 0x1;
+
+// Decodes a `security.capability` value into the getcap(8) form, like
+// `cap_net_admin,cap_net_raw+ep`. Capabilities sharing the same flags are
+// grouped. None for a value that isn't valid vfs_cap_data.
+pub fn decode(data: &[u8]) -> Option<String> {
+    let word = |i: usize| data.get(i * 4..i * 4 + 4).map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]));
+
+    let magic = word(0)?;
+    // Revision 1 has one 32 bit set, 2 and 3 have two, 3 adds a root uid after them
+    let (words, len) = match magic & REVISION_MASK {
+        0x01000000 => (1, 12),
+        0x02000000 => (2, 20),
+        0x03000000 => (2, 24),
+        _ => return None
+    };
+    if data.len() != len {
+        return None;
+    }
+
+    let (mut permitted, mut inheritable) = (0u64, 0u64);
+    for i in 0..words {
+        permitted |= (word(1 + i * 2)? as u64) << (32 * i);
+        inheritable |= (word(2 + i * 2)? as u64) << (32 * i);
+    }
+    let effective = magic & FLAG_EFFECTIVE != 0;
+
+    // Grouped in order of the first capability with each set of flags
+    let mut groups: Vec<(String, Vec<String>)> = vec![];
+    for bit in 0..64 {
+        let (p, i) = (permitted & (1 << bit) != 0, inheritable & (1 << bit) != 0);
+        if !p && !i {
+            continue;
+        }
+
+        let flags = format!("{}{}{}", if effective { "e" } else { "" }, if i { "i" } else { "" }, if p { "p" } else { "" });
+        let name = NAMES.get(bit).map_or_else(|| bit.to_string(), |name| format!("cap_{}", name));
+        match groups.iter_mut().find(|(group_flags, _)| *group_flags == flags) {
+            Some((_, names)) => names.push(name),
+            None => groups.push((flags, vec![name]))
+        }
+    }
+
+    Some(groups
+        .iter()
+        .map(|(flags, names)| format!("{}+{}", names.join(","), flags))
+        .collect::<Vec<String>>()
+        .join(" "))
+}
@@ -0,0 +1,482 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+use crate::params::Checksum;
+
+// The hash of a file as lowercase hex, read in pieces rather than all at once
+pub fn file(path: &Path, kind: &Checksum) -> io::Result<String> {
+    let mut hasher = match kind {
+        Checksum::Md5 => Hasher::Md5(Md5::new()),
+        Checksum::Sha256 => Hasher::Sha256(Sha256::new()),
+        Checksum::Blake3 => Hasher::Blake3(Box::new(Blake3::new()))
+    };
+
+    let mut file = File::open(path)?;
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        match file.read(&mut buf) {
+            Ok(0) => break,
+            Ok(len) => hasher.update(&buf[..len]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e)
+        }
+    }
+
+    Ok(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+enum Hasher {
+    Md5(Md5),
+    Sha256(Sha256),
+    Blake3(Box<Blake3>)
+}
+
+impl Hasher {
+    fn update(&mut self, input: &[u8]) {
+        match self {
+            Self::Md5(hasher) => hasher.update(input),
+            Self::Sha256(hasher) => hasher.update(input),
+            Self::Blake3(hasher) => hasher.update(input)
+        }
+    }
+
+    fn finalize(self) -> Vec<u8> {
+        match self {
+            Self::Md5(hasher) => hasher.finalize().to_vec(),
+            Self::Sha256(hasher) => hasher.finalize().to_vec(),
+            Self::Blake3(hasher) => hasher.finalize().to_vec()
+        }
+    }
+}
+
+// MD5 and SHA-256 share the Merkle–Damgård padding: a 1 bit, zeros, then the
+// length in bits, big endian for SHA-256 and little endian for MD5.
+struct Blocks {
+    block: [u8; 64],
+    len: usize,
+    total: u64
+}
+
+impl Blocks {
+    fn new() -> Self {
+        Self { block: [0; 64], len: 0, total: 0 }
+    }
+
+    fn update(&mut self, mut input: &[u8], mut compress: impl FnMut(&[u8; 64])) {
+        self.total += input.len() as u64;
+        while !input.is_empty() {
+            let take = (64 - self.len).min(input.len());
+            self.block[self.len..self.len + take].copy_from_slice(&input[..take]);
+            self.len += take;
+            input = &input[take..];
+
+            if self.len == 64 {
+                compress(&self.block);
+                self.len = 0;
+            }
+        }
+    }
+
+    fn finish(&mut self, length: [u8; 8], mut compress: impl FnMut(&[u8; 64])) {
+        self.block[self.len] = 0x80;
+        self.block[self.len + 1..].fill(0);
+        if self.len >= 56 {
+            compress(&self.block);
+            self.block.fill(0);
+        }
+        self.block[56..].copy_from_slice(&length);
+        compress(&self.block);
+    }
+}
+
+struct Md5 {
+    state: [u32; 4],
+    blocks: Blocks
+}
+
+impl Md5 {
+    const SHIFTS: [u32; 64] = [
+        7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22,
+        5, 9, 14, 20, 5, 9, 14, 20, 5, 9, 14, 20, 5, 9, 14, 20,
+        4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23,
+        6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21
+    ];
+
+    // The integer part of 2^32 * |sin(i + 1)|
+    const K: [u32; 64] = [
+        0xd76aa478, 0xe8c7b756, 0x242070db, 0xc1bdceee, 0xf57c0faf, 0x4787c62a, 0xa8304613, 0xfd469501,
+        0x698098d8, 0x8b44f7af, 0xffff5bb1, 0x895cd7be, 0x6b901122, 0xfd987193, 0xa679438e, 0x49b40821,
+        0xf61e2562, 0xc040b340, 0x265e5a51, 0xe9b6c7aa, 0xd62f105d, 0x02441453, 0xd8a1e681, 0xe7d3fbc8,
+        0x21e1cde6, 0xc33707d6, 0xf4d50d87, 0x455a14ed, 0xa9e3e905, 0xfcefa3f8, 0x676f02d9, 0x8d2a4c8a,
+        0xfffa3942, 0x8771f681, 0x6d9d6122, 0xfde5380c, 0xa4beea44, 0x4bdecfa9, 0xf6bb4b60, 0xbebfbc70,
+        0x289b7ec6, 0xeaa127fa, 0xd4ef3085, 0x04881d05, 0xd9d4d039, 0xe6db99e5, 0x1fa27cf8, 0xc4ac5665,
+        0xf4292244, 0x432aff97, 0xab9423a7, 0xfc93a039, 0x655b59c3, 0x8f0ccc92, 0xffeff47d, 0x85845dd1,
+        0x6fa87e4f, 0xfe2ce6e0, 0xa3014314, 0x4e0811a1, 0xf7537e82, 0xbd3af235, 0x2ad7d2bb, 0xeb86d391
+    ];
+
+    fn new() -> Self {
+        Self {
+            state: [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476],
+            blocks: Blocks::new()
+        }
+    }
+
+    fn compress(state: &mut [u32; 4], block: &[u8; 64]) {
+        let words: Vec<u32> = block.chunks_exact(4).map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]])).collect();
+        let [mut a, mut b, mut c, mut d] = *state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16)
+            };
+            let f = f.wrapping_add(a).wrapping_add(Self::K[i]).wrapping_add(words[g]);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(f.rotate_left(Self::SHIFTS[i]));
+        }
+
+        for (value, add) in state.iter_mut().zip([a, b, c, d]) {
+            *value = value.wrapping_add(add);
+        }
+    }
+
+    fn update(&mut self, input: &[u8]) {
+        let state = &mut self.state;
+        self.blocks.update(input, |block| Self::compress(state, block));
+    }
+
+    fn finalize(mut self) -> [u8; 16] {
+        let state = &mut self.state;
+        let length = (self.blocks.total * 8).to_le_bytes();
+        self.blocks.finish(length, |block| Self::compress(state, block));
+
+        let mut out = [0; 16];
+        for (chunk, word) in out.chunks_exact_mut(4).zip(self.state) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        out
+    }
+}
+
+struct Sha256 {
+    state: [u32; 8],
+    blocks: Blocks
+}
+
+impl Sha256 {
+    const IV: [u32; 8] = [0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19];
+    const K: [u32; 64] = [
+        0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+        0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+        0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+        0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+        0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+        0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+        0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+        0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2
+    ];
+
+    fn new() -> Self {
+        Self {
+            state: Self::IV,
+            blocks: Blocks::new()
+        }
+    }
+
+    fn compress(state: &mut [u32; 8], block: &[u8; 64]) {
+        let mut w = [0u32; 64];
+        for (i, chunk) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = *state;
+        for (k, w) in Self::K.iter().zip(w) {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h.wrapping_add(s1).wrapping_add(ch).wrapping_add(*k).wrapping_add(w);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+
+        for (value, add) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *value = value.wrapping_add(add);
+        }
+    }
+
+    fn update(&mut self, input: &[u8]) {
+        let state = &mut self.state;
+        self.blocks.update(input, |block| Self::compress(state, block));
+    }
+
+    fn finalize(mut self) -> [u8; 32] {
+        let state = &mut self.state;
+        let length = (self.blocks.total * 8).to_be_bytes();
+        self.blocks.finish(length, |block| Self::compress(state, block));
+
+        let mut out = [0; 32];
+        for (chunk, word) in out.chunks_exact_mut(4).zip(self.state) {
+            chunk.copy_from_slice(&word.to_be_bytes());
+        }
+        out
+    }
+}
+
+// BLAKE3 reuses the SHA-256 initial state
+const BLAKE3_IV: [u32; 8] = Sha256::IV;
+const BLAKE3_PERMUTATION: [usize; 16] = [2, 6, 3, 10, 7, 0, 4, 13, 1, 11, 12, 5, 9, 14, 15, 8];
+const CHUNK_START: u32 = 1;
+const CHUNK_END: u32 = 2;
+const PARENT: u32 = 4;
+const ROOT: u32 = 8;
+const CHUNK_LEN: usize = 1024;
+
+fn blake3_g(state: &mut [u32; 16], [a, b, c, d]: [usize; 4], mx: u32, my: u32) {
+    state[a] = state[a].wrapping_add(state[b]).wrapping_add(mx);
+    state[d] = (state[d] ^ state[a]).rotate_right(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_right(12);
+    state[a] = state[a].wrapping_add(state[b]).wrapping_add(my);
+    state[d] = (state[d] ^ state[a]).rotate_right(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_right(7);
+}
+
+fn blake3_compress(chaining_value: &[u32; 8], block: &[u32; 16], counter: u64, block_len: u32, flags: u32) -> [u32; 16] {
+    let mut state = [0; 16];
+    state[..8].copy_from_slice(chaining_value);
+    state[8..12].copy_from_slice(&BLAKE3_IV[..4]);
+    state[12..].copy_from_slice(&[counter as u32, (counter >> 32) as u32, block_len, flags]);
+
+    let mut m = *block;
+    for round in 0..7 {
+        blake3_g(&mut state, [0, 4, 8, 12], m[0], m[1]);
+        blake3_g(&mut state, [1, 5, 9, 13], m[2], m[3]);
+        blake3_g(&mut state, [2, 6, 10, 14], m[4], m[5]);
+        blake3_g(&mut state, [3, 7, 11, 15], m[6], m[7]);
+        blake3_g(&mut state, [0, 5, 10, 15], m[8], m[9]);
+        blake3_g(&mut state, [1, 6, 11, 12], m[10], m[11]);
+        blake3_g(&mut state, [2, 7, 8, 13], m[12], m[13]);
+        blake3_g(&mut state, [3, 4, 9, 14], m[14], m[15]);
+        if round < 6 {
+            m = BLAKE3_PERMUTATION.map(|i| m[i]);
+        }
+    }
+
+    for i in 0..8 {
+        state[i] ^= state[i + 8];
+        state[i + 8] ^= chaining_value[i];
+    }
+    state
+}
+
+fn first_8_words(words: [u32; 16]) -> [u32; 8] {
+    let mut out = [0; 8];
+    out.copy_from_slice(&words[..8]);
+    out
+}
+
+fn le_words(bytes: &[u8; 64]) -> [u32; 16] {
+    let mut words = [0; 16];
+    for (word, chunk) in words.iter_mut().zip(bytes.chunks_exact(4)) {
+        *word = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+    }
+    words
+}
+
+// A compression that hasn't run yet, so the last one can still be flagged as the root
+struct Blake3Output {
+    chaining_value: [u32; 8],
+    block: [u32; 16],
+    counter: u64,
+    block_len: u32,
+    flags: u32
+}
+
+impl Blake3Output {
+    fn chaining_value(&self) -> [u32; 8] {
+        first_8_words(blake3_compress(&self.chaining_value, &self.block, self.counter, self.block_len, self.flags))
+    }
+
+    fn root_hash(&self) -> [u8; 32] {
+        let words = blake3_compress(&self.chaining_value, &self.block, 0, self.block_len, self.flags | ROOT);
+        let mut out = [0; 32];
+        for (chunk, word) in out.chunks_exact_mut(4).zip(words) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        out
+    }
+
+    fn parent(left: [u32; 8], right: [u32; 8]) -> Self {
+        let mut block = [0; 16];
+        block[..8].copy_from_slice(&left);
+        block[8..].copy_from_slice(&right);
+        Self { chaining_value: BLAKE3_IV, block, counter: 0, block_len: 64, flags: PARENT }
+    }
+}
+
+struct Blake3Chunk {
+    chaining_value: [u32; 8],
+    counter: u64,
+    block: [u8; 64],
+    block_len: usize,
+    blocks_compressed: usize
+}
+
+impl Blake3Chunk {
+    fn new(counter: u64) -> Self {
+        Self { chaining_value: BLAKE3_IV, counter, block: [0; 64], block_len: 0, blocks_compressed: 0 }
+    }
+
+    fn len(&self) -> usize {
+        64 * self.blocks_compressed + self.block_len
+    }
+
+    fn start_flag(&self) -> u32 {
+        if self.blocks_compressed == 0 { CHUNK_START } else { 0 }
+    }
+
+    fn update(&mut self, mut input: &[u8]) {
+        while !input.is_empty() {
+            // The last block is held back until it's known to be the last
+            if self.block_len == 64 {
+                let words = le_words(&self.block);
+                self.chaining_value = first_8_words(blake3_compress(&self.chaining_value, &words, self.counter, 64, self.start_flag()));
+                self.blocks_compressed += 1;
+                self.block = [0; 64];
+                self.block_len = 0;
+            }
+
+            let take = (64 - self.block_len).min(input.len());
+            self.block[self.block_len..self.block_len + take].copy_from_slice(&input[..take]);
+            self.block_len += take;
+            input = &input[take..];
+        }
+    }
+
+    fn output(&self) -> Blake3Output {
+        Blake3Output {
+            chaining_value: self.chaining_value,
+            block: le_words(&self.block),
+            counter: self.counter,
+            block_len: self.block_len as u32,
+            flags: self.start_flag() | CHUNK_END
+        }
+    }
+}
+
+// The incremental tree hash of the BLAKE3 reference implementation, without
+// keyed hashing or extended output
+struct Blake3 {
+    chunk: Blake3Chunk,
+    // Chaining values of finished subtrees, merged as soon as a pair is complete
+    stack: Vec<[u32; 8]>
+}
+
+impl Blake3 {
+    fn new() -> Self {
+        Self { chunk: Blake3Chunk::new(0), stack: vec![] }
+    }
+
+    fn update(&mut self, mut input: &[u8]) {
+        while !input.is_empty() {
+            if self.chunk.len() == CHUNK_LEN {
+                let mut chaining_value = self.chunk.output().chaining_value();
+                let mut total_chunks = self.chunk.counter + 1;
+                while total_chunks & 1 == 0 {
+                    let left = self.stack.pop().unwrap_or_default();
+                    chaining_value = Blake3Output::parent(left, chaining_value).chaining_value();
+                    total_chunks >>= 1;
+                }
+                self.stack.push(chaining_value);
+                self.chunk = Blake3Chunk::new(self.chunk.counter + 1);
+            }
+
+            let take = (CHUNK_LEN - self.chunk.len()).min(input.len());
+            self.chunk.update(&input[..take]);
+            input = &input[take..];
+        }
+    }
+
+    fn finalize(self) -> [u8; 32] {
+        let mut output = self.chunk.output();
+        for left in self.stack.iter().rev() {
+            output = Blake3Output::parent(*left, output.chaining_value());
+        }
+        output.root_hash()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    // Hashes `input` fed in pieces of `step` bytes, to cross block and chunk boundaries
+    fn digest(kind: &Checksum, input: &[u8], step: usize) -> String {
+        let mut hasher = match kind {
+            Checksum::Md5 => Hasher::Md5(Md5::new()),
+            Checksum::Sha256 => Hasher::Sha256(Sha256::new()),
+            Checksum::Blake3 => Hasher::Blake3(Box::new(Blake3::new()))
+        };
+        input.chunks(step.max(1)).for_each(|piece| hasher.update(piece));
+        hex(&hasher.finalize())
+    }
+
+    // The input of the BLAKE3 test vectors, bytes counting up modulo 251
+    fn counting(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn md5_vectors() {
+        assert_eq!(digest(&Checksum::Md5, b"", 1), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(digest(&Checksum::Md5, b"abc", 1), "900150983cd24fb0d6963f7d28e17f72");
+        assert_eq!(digest(&Checksum::Md5, &counting(3000), 1000), "a216503cb86d01a23e71e047d4ccf001");
+    }
+
+    #[test]
+    fn sha256_vectors() {
+        assert_eq!(digest(&Checksum::Sha256, b"", 1), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(digest(&Checksum::Sha256, b"abc", 1), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(digest(&Checksum::Sha256, &counting(3000), 1000), "e8ca4bf83f56152c01649f88bd7c91b15ae8137d9a709572e04fae55894ea75e");
+    }
+
+    #[test]
+    fn blake3_vectors() {
+        assert_eq!(digest(&Checksum::Blake3, b"", 1), "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262");
+        assert_eq!(digest(&Checksum::Blake3, &counting(1), 1), "2d3adedff11b61f14c886e35afa036736dcd87a74d27b5c1510225d0f592e213");
+        assert_eq!(digest(&Checksum::Blake3, b"abc", 1), "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85");
+    }
+
+    #[test]
+    fn digests_do_not_depend_on_how_input_is_split() {
+        let input = counting(5000);
+        for kind in [Checksum::Md5, Checksum::Sha256, Checksum::Blake3] {
+            let whole = digest(&kind, &input, input.len());
+            for step in [1, 63, 64, 65, 1023, 1024, 1025] {
+                assert_eq!(digest(&kind, &input, step), whole);
+            }
+        }
+    }
+}
@@ -1,47 +1,211 @@
-use std::{collections::HashMap, sync::OnceLock};
+use std::{collections::HashMap, fs, path::{Path, PathBuf}, sync::OnceLock};
 use std::env;
 
 use colored::{ColoredString, Colorize};
 
-use crate::utils::StrUtil;
+use crate::error::{self, KlsError};
+use crate::utils::glob_match;
 
-fn get_cached_map() -> &'static HashMap<String, Vec<String>> {
-    static CACHE: OnceLock<HashMap<String, Vec<String>>> = OnceLock::new();
+struct ColorMap {
+    types: HashMap<String, Vec<String>>,
+    // Name patterns like `*.tar.gz` or `*README` in the order given, later ones win
+    patterns: Vec<(String, Vec<String>)>
+}
+
+static CACHE: OnceLock<ColorMap> = OnceLock::new();
+
+const DIRCOLORS_KEYS: [(&str, &str); 27] = [
+    ("NORMAL", "no"), ("NORM", "no"), ("FILE", "fi"), ("RESET", "rs"),
+    ("DIR", "di"), ("LNK", "ln"), ("LINK", "ln"), ("SYMLINK", "ln"),
+    ("ORPHAN", "or"), ("MISSING", "mi"), ("FIFO", "pi"), ("PIPE", "pi"),
+    ("SOCK", "so"), ("BLK", "bd"), ("BLOCK", "bd"), ("CHR", "cd"),
+    ("CHAR", "cd"), ("DOOR", "do"), ("EXEC", "ex"), ("SETUID", "su"),
+    ("SETGID", "sg"), ("STICKY_OTHER_WRITABLE", "tw"), ("OWR", "ow"),
+    ("OTHER_WRITABLE", "ow"), ("STICKY", "st"), ("CAPABILITY", "ca"),
+    ("MULTIHARDLINK", "mh")
+];
+
+fn dircolors_key(keyword: &str) -> Option<&'static str> {
+    let keyword = keyword.to_uppercase();
+
+    DIRCOLORS_KEYS
+        .iter()
+        .find(|(k, _)| *k == keyword)
+        .map(|(_, v)| *v)
+}
+
+// Translates a dircolors(1) database into LS_COLORS style key/value pairs,
+// keeping only the entries whose TERM/COLORTERM section matches.
+pub fn parse_dircolors(content: &str, term: &str, colorterm: &str) -> Vec<(String, String)> {
+    let mut entries = vec![];
+    let mut matched = true;
+    let mut in_term_block = false;
+
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (keyword, value) = match line.find(char::is_whitespace) {
+            Some(i) => (&line[..i], line[i..].trim_start()),
+            None => (line, "")
+        };
+        let value = match value.find('#') {
+            Some(i) => &value[..i],
+            None => value
+        }.trim();
 
-    CACHE.get_or_init(|| {
-        let mut map = HashMap::new();
+        let upper = keyword.to_uppercase();
+        if upper == "TERM" || upper == "COLORTERM" {
+            if !in_term_block {
+                matched = false;
+                in_term_block = true;
+            }
 
-        if let Ok(col_str) = env::var("LS_COLORS") {
-            let entries: Vec<&str> = col_str.split(":").collect();
+            let current = if upper == "TERM" { term } else { colorterm };
+            if glob_match(value, current) {
+                matched = true;
+            }
+            continue;
+        }
+        in_term_block = false;
 
-            for entry in entries {
-                let pair: Vec<&str> = entry.split("=").collect();
-                if pair.len() != 2 {
-                    continue;
-                }
+        if !matched || value.is_empty() {
+            continue;
+        }
 
-                let name = pair.get(0).unwrap().to_string();
-                let color = pair.get(1).unwrap();
+        let key = if keyword.starts_with('.') {
+            format!("*{}", keyword)
+        } else if keyword.starts_with('*') {
+            keyword.to_string()
+        } else if let Some(key) = dircolors_key(keyword) {
+            key.to_string()
+        } else {
+            // COLOR, OPTIONS and EIGHTBIT are recognized but ignored like dircolors does
+            continue;
+        };
 
-                if let Some(index) = name.rfind('.').map(|i| i+1) {
-                    let name = name.substr_after(index);
+        entries.push((key, value.to_string()));
+    }
 
-                    let color: Vec<String> = color
-                        .split(";")
-                        .map(|l| l.to_string())
-                        .collect();
+    entries
+}
 
-                    map.insert(name, color);
-                }
+fn read_dircolors(path: &Path, explicit: bool) -> Vec<(String, String)> {
+    match fs::read_to_string(path) {
+        Ok(content) => parse_dircolors(
+            &content,
+            &env::var("TERM").unwrap_or_default(),
+            &env::var("COLORTERM").unwrap_or_default()),
+        Err(e) => {
+            if explicit {
+                error::report(&format!("cannot read colors from '{}'", path.to_str().unwrap_or("Unknown path")), &e);
             }
+            vec![]
+        }
+    }
+}
+
+const TYPE_KEYS: [&str; 24] = [
+    "no", "fi", "rs", "di", "ln", "or", "mi", "pi", "so", "bd", "cd", "do",
+    "ex", "su", "sg", "tw", "ow", "st", "ca", "mh", "lc", "rc", "ec", "cl"
+];
+
+fn split_entry(entry: &str) -> Option<(String, String)> {
+    let pair: Vec<&str> = entry.split("=").collect();
+    if pair.len() != 2 {
+        return None;
+    }
+
+    Some((pair[0].to_string(), pair[1].to_string()))
+}
+
+fn parse_ls_colors(col_str: &str) -> Vec<(String, String)> {
+    col_str
+        .split(":")
+        .filter_map(split_entry)
+        .collect()
+}
+
+// Parses a single LS_COLORS style `KEY=SGR` assignment, validating both sides
+pub fn parse_color_entry(entry: &str) -> Result<(String, String), KlsError> {
+    let invalid = || KlsError::S(format!("Invalid color assignment: '{}'", entry));
+    let (key, color) = split_entry(entry).ok_or_else(invalid)?;
+
+    let valid_key = (key.starts_with('*') && key.len() > 1) || TYPE_KEYS.contains(&key.as_str());
+    let valid_color = !color.is_empty() && (color.chars().all(|c| c.is_ascii_digit() || c == ';') || (key == "ln" && color == "target"));
+
+    if valid_key && valid_color {
+        Ok((key, color))
+    } else {
+        Err(invalid())
+    }
+}
+
+fn build_map(colors_from: Option<&Path>, overrides: &[(String, String)]) -> ColorMap {
+    let mut map = ColorMap {
+        types: HashMap::new(),
+        patterns: vec![]
+    };
+
+    let mut entries = match colors_from {
+        Some(path) => read_dircolors(path, true),
+        None => match env::var("HOME") {
+            Ok(home) => read_dircolors(&PathBuf::from(home).join(".dir_colors"), false),
+            Err(_) => vec![]
+        }
+    };
+
+    // LS_COLORS entries and then --color-override come last so they take precedence
+    if let Ok(col_str) = env::var("LS_COLORS") {
+        entries.extend(parse_ls_colors(&col_str));
+    }
+    entries.extend(overrides.iter().cloned());
+
+    for (name, color) in entries {
+        let color: Vec<String> = color
+            .split(";")
+            .map(|l| l.to_string())
+            .collect();
+
+        if name.starts_with('*') {
+            map.patterns.push((name, color));
+        } else {
+            map.types.insert(name, color);
         }
+    }
 
-        map
-    })
+    map
 }
 
-pub fn compute_color_for(on: ColoredString, text: &String) -> ColoredString {
-    match get_cached_map().get(text) {
+pub fn init_colors(colors_from: Option<&Path>, overrides: &[(String, String)]) {
+    CACHE.get_or_init(|| build_map(colors_from, overrides));
+}
+
+fn get_cached_map() -> &'static ColorMap {
+    CACHE.get_or_init(|| build_map(None, &[]))
+}
+
+// Styles `text` with the color configured for a file type key like `di`
+pub fn compute_type_color(text: &str, key: &str) -> Option<ColoredString> {
+    let codes = get_cached_map().types.get(key)?;
+
+    Some(codes
+        .iter()
+        .fold(ColoredString::from(text), |colored_str, code| compute_on(colored_str, code.as_str())))
+}
+
+// Styles a name with the last pattern matching the whole file name
+pub fn compute_color_for(on: ColoredString, file_name: &str) -> ColoredString {
+    let codes = get_cached_map()
+        .patterns
+        .iter()
+        .rev()
+        .find(|(pattern, _)| glob_match(pattern, file_name))
+        .map(|(_, codes)| codes);
+
+    match codes {
         None => on,
         Some(codes) => {
             let mut colored_str = on.clone();
@@ -62,7 +226,7 @@ pub fn compute_color_for(on: ColoredString, text: &String) -> ColoredString {
 }
 
 pub fn compute_on<T: Colorize + Clone>(on: T, code: &str) -> ColoredString where ColoredString: From<T> {
-    match code {
+    match code.trim_start_matches('0') {
         "30" => on.black(),
         "31" => on.red(),
         "32" => on.green(),
@@ -79,12 +243,100 @@ pub fn compute_on<T: Colorize + Clone>(on: T, code: &str) -> ColoredString where
         "45" => on.on_magenta(),
         "46" => on.on_cyan(),
         "47" => on.on_white(),
+        "90" => on.bright_black(),
+        "91" => on.bright_red(),
+        "92" => on.bright_green(),
+        "93" => on.bright_yellow(),
+        "94" => on.bright_blue(),
+        "95" => on.bright_magenta(),
+        "96" => on.bright_cyan(),
+        "97" => on.bright_white(),
         "1" => on.bold(),
+        "2" => on.dimmed(),
+        "3" => on.italic(),
         "4" => on.underline(),
         "5" => on.blink(),
         "7" => on.reversed(),
+        "8" => on.hidden(),
+        "9" => on.strikethrough(),
         _ => ColoredString::from(on)
     }
 }
 
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairs(entries: &[(&str, &str)]) -> Vec<(String, String)> {
+        entries.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn dircolors_keywords_and_extensions() {
+        let content = "\
+# comment
+COLOR tty
+DIR 01;34 # directories
+link 01;36
+.tar 01;31
+*README 00;33
+OPTIONS -F
+";
+        assert_eq!(parse_dircolors(content, "xterm", ""),
+            pairs(&[("di", "01;34"), ("ln", "01;36"), ("*.tar", "01;31"), ("*README", "00;33")]));
+    }
+
+    #[test]
+    fn dircolors_term_blocks() {
+        let content = "\
+DIR 01;34
+TERM xterm*
+TERM screen
+EXEC 01;32
+COLORTERM ?*
+FIFO 33
+TERM linux
+SOCK 35
+";
+        assert_eq!(parse_dircolors(content, "xterm-256color", ""),
+            pairs(&[("di", "01;34"), ("ex", "01;32")]));
+        assert_eq!(parse_dircolors(content, "screen", "truecolor"),
+            pairs(&[("di", "01;34"), ("ex", "01;32"), ("pi", "33")]));
+        assert_eq!(parse_dircolors(content, "linux", ""),
+            pairs(&[("di", "01;34"), ("so", "35")]));
+        assert_eq!(parse_dircolors(content, "dumb", ""), pairs(&[("di", "01;34")]));
+    }
+
+    #[test]
+    fn stock_dircolors_database() {
+        // The default database as printed by coreutils' dircolors -p
+        let content = include_str!("testdata/DIR_COLORS");
+        let lookup = |entries: &[(String, String)], key: &str| {
+            entries.iter().find(|(k, _)| k == key).map(|(_, v)| v.clone())
+        };
+
+        for (term, colorterm) in [("xterm-256color", ""), ("dumb", "truecolor")] {
+            let entries = parse_dircolors(content, term, colorterm);
+            assert_eq!(lookup(&entries, "di").as_deref(), Some("01;34"));
+            assert_eq!(lookup(&entries, "ln").as_deref(), Some("01;36"));
+            assert_eq!(lookup(&entries, "ex").as_deref(), Some("01;32"));
+            assert_eq!(lookup(&entries, "*.tar").as_deref(), Some("01;31"));
+            assert_eq!(lookup(&entries, "rs").as_deref(), Some("0"));
+            assert_eq!(lookup(&entries, "mh").as_deref(), Some("00"));
+        }
+        // Everything in it sits under TERM/COLORTERM lines
+        assert!(parse_dircolors(content, "dumb", "").is_empty());
+    }
+
+    #[test]
+    fn color_entries() {
+        assert_eq!(parse_color_entry("di=01;34").unwrap(), (String::from("di"), String::from("01;34")));
+        assert_eq!(parse_color_entry("ln=target").unwrap(), (String::from("ln"), String::from("target")));
+        assert!(parse_color_entry("*.rs=32").is_ok());
+        for entry in ["di", "xx=31", "*=31", "di=", "di=red", "fi=target", "a=b=c"] {
+            assert!(parse_color_entry(entry).is_err(), "{:?} should be rejected", entry);
+        }
+    }
+}
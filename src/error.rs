@@ -1,5 +1,6 @@
-use std::fmt;
+use std::{fmt, io};
 use std::error::Error;
+use std::sync::{Mutex, OnceLock};
 
 #[derive(Debug)]
 #[allow(dead_code)]
@@ -19,3 +20,106 @@ impl fmt::Display for KlsError {
 
 impl Error for KlsError {}
 
+
+struct ErrorReport {
+    quiet: bool,
+    strict: bool,
+    fatal: Option<String>,
+    counts: Vec<(&'static str, usize)>
+}
+
+fn get_report() -> &'static Mutex<ErrorReport> {
+    static REPORT: OnceLock<Mutex<ErrorReport>> = OnceLock::new();
+
+    REPORT.get_or_init(|| Mutex::new(ErrorReport {
+        quiet: false,
+        strict: false,
+        fatal: None,
+        counts: vec![]
+    }))
+}
+
+fn error_category(e: &(dyn Error + 'static)) -> &'static str {
+    if let Some(e) = e.downcast_ref::<io::Error>() {
+        match e.kind() {
+            io::ErrorKind::PermissionDenied => "permission denied",
+            io::ErrorKind::NotFound => "not found",
+            _ => "other"
+        }
+    } else if let Some(KlsError::E(_, e)) = e.downcast_ref::<KlsError>() {
+        error_category(e.as_ref())
+    } else {
+        #[cfg(unix)]
+        {
+            use nix::errno::Errno;
+
+            match e.downcast_ref::<Errno>() {
+                Some(Errno::EACCES) | Some(Errno::EPERM) => "permission denied",
+                Some(Errno::ENOENT) => "not found",
+                _ => "other"
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            "other"
+        }
+    }
+}
+
+pub fn init_report(quiet: bool, strict: bool) {
+    let mut report = get_report().lock().unwrap();
+    report.quiet = quiet;
+    report.strict = strict;
+}
+
+// Records a non-fatal error, printing it unless --quiet-errors is active.
+// Under --strict the first error is kept instead, see `check_strict`.
+pub fn report(msg: &str, e: &(dyn Error + 'static)) {
+    let mut report = get_report().lock().unwrap();
+    if report.strict {
+        if report.fatal.is_none() {
+            report.fatal = Some(format!("{}: {}", msg, e));
+        }
+        return;
+    }
+
+    let category = error_category(e);
+
+    match report.counts.iter_mut().find(|(c, _)| *c == category) {
+        Some((_, count)) => *count += 1,
+        None => report.counts.push((category, 1))
+    }
+
+    if !report.quiet {
+        eprintln!("kls: {}: {}", msg, e);
+    }
+}
+
+pub fn check_strict() -> Result<(), KlsError> {
+    match &get_report().lock().unwrap().fatal {
+        None => Ok(()),
+        Some(msg) => Err(KlsError::S(msg.clone()))
+    }
+}
+
+// Prints the summary line and returns whether any errors were reported
+pub fn finish_report() -> bool {
+    let report = get_report().lock().unwrap();
+    let total: usize = report.counts.iter().map(|(_, c)| c).sum();
+    if total == 0 {
+        return false;
+    }
+
+    let details: Vec<String> = report.counts
+        .iter()
+        .map(|(category, count)| format!("{}: {}", category, count))
+        .collect();
+
+    eprintln!("kls: {} error{} encountered ({}){}",
+        total,
+        if total == 1 { "" } else { "s" },
+        details.join(", "),
+        if report.quiet { "; rerun with --verbose for details" } else { "" });
+
+    true
+}
@@ -1,33 +1,90 @@
 use std::error::Error;
-use std::path::PathBuf;
+use std::borrow::Cow;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
 use std::{fmt, fs};
-use chrono::{DateTime, Local, Duration, Datelike, Timelike, Utc};
-use crate::error::KlsError;
-use crate::params::Opts;
-use crate::utils::{StrUtil, PathUtil};
+use std::cmp::Ordering;
+use std::ops::Range;
+use std::sync::OnceLock;
+use std::time::SystemTime;
+use chrono::{DateTime, Local};
+use crate::archive;
+use crate::caps;
+use crate::error::{self, KlsError};
+use crate::gitignore::GitIgnore;
+use crate::gitstatus::GitStatus;
+use crate::params::{Collation, Only, Opts, SortMode, TimeField};
+use crate::size::{self, BlockSize};
+use crate::utils::{glob_match, glob_match_spans, PathUtil};
+use crate::xattr;
 
 pub enum FilesType {
     Dir(PathBuf),
     File(PathBuf),
-    Sym(PathBuf, PathBuf)
+    Sym(PathBuf, PathBuf),
+    Block(PathBuf),
+    Char(PathBuf),
+    Fifo(PathBuf),
+    Socket(PathBuf)
 }
 
 impl FilesType {
     pub fn path(&self) -> &PathBuf {
         match self {
-            FilesType::Dir(p) => &p,
-            FilesType::File(p) => &p,
-            FilesType::Sym(s, _) => &s
+            FilesType::Dir(p) => p,
+            FilesType::File(p) => p,
+            FilesType::Sym(s, _) => s,
+            FilesType::Block(p) => p,
+            FilesType::Char(p) => p,
+            FilesType::Fifo(p) => p,
+            FilesType::Socket(p) => p
         }
     }
+
+    // Anything that isn't a directory or symlink
+    fn other(path: PathBuf, kind: &fs::FileType) -> FilesType {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::FileTypeExt;
+            if kind.is_block_device() {
+                return FilesType::Block(path);
+            }
+            if kind.is_char_device() {
+                return FilesType::Char(path);
+            }
+            if kind.is_fifo() {
+                return FilesType::Fifo(path);
+            }
+            if kind.is_socket() {
+                return FilesType::Socket(path);
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = kind;
+
+        FilesType::File(path)
+    }
 }
 
 impl FilesType {
+    // Symlinks count as what they point to, broken ones as files
+    pub fn targets_dir(&self) -> bool {
+        match self {
+            FilesType::Dir(_) => true,
+            FilesType::Sym(s, _) => s.is_dir(),
+            _ => false
+        }
+    }
+
     pub fn canonicalize(&self) -> Result<FilesType, KlsError> {
         match self {
             FilesType::Dir(p) => Ok(FilesType::Dir(p.kabsolute()?)),
             FilesType::File(p) => Ok(FilesType::File(p.kabsolute()?)),
-            FilesType::Sym(s, p) => Ok(FilesType::Sym(s.kabsolute()?, p.to_owned()))
+            FilesType::Sym(s, p) => Ok(FilesType::Sym(s.kabsolute()?, p.to_owned())),
+            FilesType::Block(p) => Ok(FilesType::Block(p.kabsolute()?)),
+            FilesType::Char(p) => Ok(FilesType::Char(p.kabsolute()?)),
+            FilesType::Fifo(p) => Ok(FilesType::Fifo(p.kabsolute()?)),
+            FilesType::Socket(p) => Ok(FilesType::Socket(p.kabsolute()?))
         }
     }
 }
@@ -35,9 +92,8 @@ impl FilesType {
 impl fmt::Display for FilesType {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            FilesType::Dir(p) => write!(f, "{}", p.kstr()),
-            FilesType::File(p) => write!(f, "{}", p.kstr()),
-            FilesType::Sym(s, p) => write!(f, "{} -> {}", s.kstr(), p.kstr())
+            FilesType::Sym(s, p) => write!(f, "{} -> {}", s.kstr(), p.kstr()),
+            _ => write!(f, "{}", self.path().kstr())
         }
     }
 }
@@ -46,7 +102,27 @@ pub struct FilesEntry {
     pub file_type: FilesType,
     perms: u16,
     pub size: u64,
-    pub modified: String
+    pub mtime: SystemTime,
+    pub atime: SystemTime,
+    pub ctime: SystemTime,
+    pub btime: Option<SystemTime>,
+    pub label: Option<OsString>,
+    // False for entries made from other data, like archive members. Their path
+    // isn't theirs on disk and must never be opened or probed.
+    pub on_disk: bool,
+    // Index and worktree status for --git, None outside a repository
+    pub git: Option<[char; 2]>,
+    pub inode: u64,
+    // The device holding the inode, the two together identify the file
+    pub dev: u64,
+    // Allocated 512 byte blocks, see `format_blocks` for the displayed value
+    pub blocks: u64,
+    pub uid: u32,
+    pub gid: u32,
+    nlink: u64,
+    rdev: u64,
+    // chflags(1) flags on macOS and FreeBSD, always 0 elsewhere
+    flags: u32
 }
 
 impl fmt::Display for FilesEntry {
@@ -54,107 +130,169 @@ impl fmt::Display for FilesEntry {
         let (path_str, path_type) = match &self.file_type {
             FilesType::Dir(p) => (p.kstr(), "Dir"),
             FilesType::File(p) => (p.kstr(), "File"),
-            FilesType::Sym(s, p) => (format!("{} -> {}", s.kstr(), p.kstr()), "Sym")
+            FilesType::Sym(s, p) => (format!("{} -> {}", s.kstr(), p.kstr()), "Sym"),
+            FilesType::Block(p) => (p.kstr(), "Block"),
+            FilesType::Char(p) => (p.kstr(), "Char"),
+            FilesType::Fifo(p) => (p.kstr(), "Fifo"),
+            FilesType::Socket(p) => (p.kstr(), "Socket")
         };
 
-        write!(f, "FilesEntry(\"{}\", {}, {:o}, {}, {})", path_str, path_type, self.perms, self.size, self.modified)
+        write!(f, "FilesEntry(\"{}\", {}, {:o}, {}, {})", path_str, path_type, self.perms, self.size, DateTime::<Local>::from(self.mtime).format("%Y-%m-%d %H:%M"))
     }
 }
 
 impl FilesEntry {
+    #[allow(dead_code)]
     pub fn new_file(path: &PathBuf, meta: fs::Metadata) -> Self {
-        Self::new(FilesType::File(path.to_owned()), meta)
+        Self::new(Self::resolve(FilesType::File(path.to_owned())), meta)
     }
 
     pub fn new_dir(path: &PathBuf, meta: fs::Metadata) -> Self {
-        Self::new(FilesType::Dir(path.to_owned()), meta)
+        Self::new(Self::resolve(FilesType::Dir(path.to_owned())), meta)
+    }
+
+    // Described by the link's own lstat data like `ls -l`, the target is only
+    // stat'ed with -L which builds a plain entry from the followed metadata
+    #[allow(dead_code)]
+    pub fn new_sym(sym: &PathBuf, path: &PathBuf) -> Result<Self, Box<dyn Error>> {
+        let meta = sym.symlink_metadata()?;
+        Ok(Self::new(Self::resolve(FilesType::Sym(sym.to_owned(), path.to_owned())), meta))
+    }
+
+    fn resolve(file_type: FilesType) -> FilesType {
+        match file_type.canonicalize() {
+            Ok(p) => p,
+            Err(e) => {
+                error::report(&format!("failed to canonicalize path \"{}\"", file_type), &e);
+                file_type
+            }
+        }
     }
 
-    pub fn new_sym(sym: &PathBuf, path: &PathBuf, meta: fs::Metadata) -> Self {
-        Self::new(FilesType::Sym(sym.to_owned(), path.to_owned()), meta)
+    // An entry that isn't on disk, like an archive member. `mode` holds the
+    // permission bits, the type comes from `file_type`.
+    pub fn new_virtual(file_type: FilesType, mode: u16, size: u64, mtime: SystemTime, (uid, gid): (u32, u32)) -> Self {
+        Self {
+            file_type,
+            perms: mode,
+            size,
+            mtime,
+            atime: mtime,
+            ctime: mtime,
+            btime: None,
+            label: None,
+            on_disk: false,
+            git: None,
+            inode: 0,
+            dev: 0,
+            blocks: size.div_ceil(512),
+            uid,
+            gid,
+            nlink: 1,
+            rdev: 0,
+            flags: 0
+        }
     }
 
-    fn new(file_type: FilesType, meta: fs::Metadata) -> Self {
+    // Expects an already absolute path, see `resolve` for the other constructors
+    pub fn new(file_type: FilesType, meta: fs::Metadata) -> Self {
         let mode: u16;
         let size: u64;
+        let ctime: SystemTime;
+        let (dev, inode, blocks, uid, gid, nlink, rdev): (u64, u64, u64, u32, u32, u64, u64);
         #[cfg(unix)]
         {
             use std::os::unix::fs::MetadataExt;
             mode = meta.mode() as u16;
             size = meta.size();
+            ctime = unix_time(meta.ctime(), meta.ctime_nsec());
+            (dev, inode, blocks, uid, gid, nlink, rdev) = (meta.dev(), meta.ino(), meta.blocks(), meta.uid(), meta.gid(), meta.nlink(), meta.rdev());
         }
         #[cfg(not(unix))]
         {
             mode = 0o0777;
             size = 0;
+            ctime = meta.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            (dev, inode, blocks, uid, gid, nlink, rdev) = (0, 0, 0, 0, 0, 0, 0);
         }
 
-        let file_type = match file_type.canonicalize() {
-            Ok(p) => p,
-            Err(e) => {
-                eprintln!("Failed to canonicalize path \"{}\": {}", file_type, e);
-                file_type
-            }
-        };
-
         Self {
             file_type,
             perms: mode,
             size,
-            modified: match meta.modified() {
-                Err(_) => String::from("Jan 01 1970"),
-                Ok(time) => {
-                    let dt: DateTime<Local> = time.into();
-
-                    let year = dt.year();
-                    let month = dt.month();
-                    let day = dt.day();
-                    let hour = dt.hour();
-                    let minute = dt.minute();
-
-                    let current_time = Utc::now();
-                    let six_months_ago = current_time - Duration::days(180);
-                    let dt = DateTime::<Utc>::from(time);
-
-                    let day = day.to_string().pad_start(2, ' ');
-                    let month = match month {
-                        1 => "Jan",
-                        2 => "Feb",
-                        3 => "Mar",
-                        4 => "Apr",
-                        5 => "May",
-                        6 => "Jun",
-                        7 => "Jul",
-                        8 => "Aug",
-                        9 => "Sep",
-                        10 => "Oct",
-                        11 => "Nov",
-                        12 => "Dec",
-                        _ => "Non"
-                    };
+            mtime: meta.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+            atime: meta.accessed().unwrap_or(SystemTime::UNIX_EPOCH),
+            ctime,
+            // std uses statx for this on Linux and st_birthtime elsewhere
+            btime: meta.created().ok(),
+            label: None,
+            on_disk: true,
+            git: None,
+            inode,
+            dev,
+            blocks,
+            uid,
+            gid,
+            nlink,
+            rdev,
+            flags: get_flags(&meta)
+        }
+    }
 
-                    let year = year.to_string();
-                    let hour = hour.to_string().pad_start(2, '0');
-                    let minute = minute.to_string().pad_start(2, '0');
-                    let time = format!("{}:{}", hour, minute);
+    // The operand itself, named the way it was given. Symlinks are only followed with `follow`.
+    pub fn from_operand(path: &PathBuf, follow: bool) -> Result<Self, Box<dyn Error>> {
+        let meta = if follow { path.metadata()? } else { path.symlink_metadata()? };
+        let kind = meta.file_type();
+        let file_type = if kind.is_symlink() {
+            FilesType::Sym(path.kabsolute()?, fs::read_link(path)?)
+        } else if kind.is_dir() {
+            FilesType::Dir(path.kabsolute()?)
+        } else {
+            FilesType::other(path.kabsolute()?, &kind)
+        };
 
-                    if dt <= six_months_ago {
-                        format!("{} {} {}", month, day, year)
-                    } else {
-                        format!("{} {} {}", month, day, time)
-                    }
-                    
-                }
-            }
-        }
+        let mut entry = Self::new(file_type, meta);
+        entry.label = Some(path.as_os_str().to_owned());
+        Ok(entry)
     }
 
     pub fn path(&self) -> &PathBuf {
         self.file_type.path()
     }
 
-    pub fn name(&self) -> Option<&str> {
-        self.path().file_name().and_then(|os| os.to_str())
+    // Only the birth time can be missing, when the filesystem doesn't record it
+    pub fn time(&self, field: &TimeField) -> Option<SystemTime> {
+        match field {
+            TimeField::Modified => Some(self.mtime),
+            TimeField::Accessed => Some(self.atime),
+            TimeField::Changed => Some(self.ctime),
+            TimeField::Birth => self.btime
+        }
+    }
+
+    // The name as stored, which doesn't have to be valid UTF-8
+    pub fn name(&self) -> Option<&OsStr> {
+        if let Some(label) = &self.label {
+            return Some(label);
+        }
+        self.path().file_name()
+    }
+
+    // For sorting and matching, with invalid bytes replaced
+    pub fn name_lossy(&self) -> Cow<'_, str> {
+        self.name().map_or(Cow::Borrowed("."), OsStr::to_string_lossy)
+    }
+
+    // Everything after the last dot, empty for dotless names and dotfiles like `.gitignore`
+    pub fn extension_of(name: &str) -> &str {
+        match name.rfind('.') {
+            None | Some(0) => "",
+            Some(i) => &name[i + 1..]
+        }
+    }
+
+    pub fn extension(&self) -> String {
+        Self::extension_of(&self.name_lossy()).to_string()
     }
 
     pub fn up_dir(&self) -> Option<PathBuf> {
@@ -166,16 +304,12 @@ impl FilesEntry {
             let meta = match p.metadata() {
                 Ok(p) => Some(p),
                 Err(e) => {
-                    eprintln!("Failed to fetch parent metadata for \"{}\": {}", p.as_os_str().to_str().unwrap_or("Unknown Path"), e);
+                    error::report(&format!("failed to fetch parent metadata for \"{}\"", p.as_os_str().to_str().unwrap_or("Unknown Path")), &e);
                     None
                 }
             };
 
-            if let Some(meta) = meta {
-                Some(FilesEntry::new_dir(&p, meta))
-            } else {
-                None
-            }
+            meta.map(|meta| FilesEntry::new_dir(&p, meta))
         })
     }
 
@@ -197,6 +331,11 @@ impl FilesEntry {
         format!("{}{}{}", rc, wc, xc)
     }
 
+    // Permission bits including setuid, setgid and sticky, without the file type
+    pub fn get_permission_bits(&self) -> u16 {
+        self.perms & 0o7777
+    }
+
     pub fn get_mode_str(&self) -> String {
         let (uid_bit, gid_bit, sticky_bit) = self.split_mode(3);
         let owner = self.split_mode(2);
@@ -207,7 +346,11 @@ impl FilesEntry {
             match self.file_type {
                 FilesType::File(_) => "-",
                 FilesType::Dir(_) => "d",
-                FilesType::Sym(_, _) => "l"
+                FilesType::Sym(_, _) => "l",
+                FilesType::Block(_) => "b",
+                FilesType::Char(_) => "c",
+                FilesType::Fifo(_) => "p",
+                FilesType::Socket(_) => "s"
             },
             Self::pick_mode(&owner, if uid_bit { b"Ss" } else { b"-x" }),
             Self::pick_mode(&group, if gid_bit { b"Ss" } else { b"-x" }),
@@ -216,111 +359,438 @@ impl FilesEntry {
     }
 
     pub fn get_group_str(&self) -> Result<String, String> {
-        let path = self.path();
-        let meta = if path.is_symlink() {
-            path.symlink_metadata()
-        } else {
-            path.metadata()
-        };
+        #[cfg(unix)]
+        {
+            use nix::unistd::{Gid, Group};
 
-        if let Ok(meta) = meta {
-            #[cfg(unix)]
-            {
-                use std::os::unix::fs::MetadataExt;
-                use nix::unistd::{Gid, Group};
+            match Group::from_gid(Gid::from_raw(self.gid)) {
+                Ok(Some(group)) => Ok(group.name),
+                Ok(None) => Ok(self.gid.to_string()),
+                Err(e) => Err(e.to_string())
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            Ok(String::new())
+        }
+    }
 
-                let gid = Gid::from_raw(meta.gid());
-                let group = Group::from_gid(gid).expect("Group fetch failed").expect("Group not found");
+    pub fn get_user_str(&self) -> Result<String, String> {
+        #[cfg(unix)]
+        {
+            use nix::unistd::{Uid, User};
 
-                Ok(group.name)
-            }
-            #[cfg(not(unix))]
-            {
-                Ok(String::new())
+            match User::from_uid(Uid::from_raw(self.uid)) {
+                Ok(Some(user)) => Ok(user.name),
+                Ok(None) => Ok(self.uid.to_string()),
+                Err(e) => Err(e.to_string())
             }
-        } else {
+        }
+        #[cfg(not(unix))]
+        {
             Ok(String::new())
         }
     }
 
-    pub fn get_user_str(&self) -> Result<String, String> {
-        let path = self.path();
-        let meta = if path.is_symlink() {
-            path.symlink_metadata()
-        } else {
-            path.metadata()
-        };
+    // Less than half of the apparent size is allocated, like with VM images.
+    // Small files are left out as some filesystems store them inline.
+    pub fn is_sparse(&self) -> bool {
+        matches!(self.file_type, FilesType::File(_)) && self.size > 4096 && self.blocks * 512 < self.size / 2
+    }
 
-        if let Ok(meta) = meta {
-            #[cfg(unix)]
-            {
-                use std::os::unix::fs::MetadataExt;
-                use nix::unistd::{Uid, User};
+    pub fn get_link_count(&self) -> u64 {
+        self.nlink
+    }
 
-                let uid = Uid::from_raw(meta.uid());
-                let user = User::from_uid(uid).expect("User fetch failed").expect("User not found");
+    // The character ls -l appends to the mode: `+` for a POSIX ACL on Linux,
+    // `.` for an SELinux context only and `@` for extended attributes on macOS
+    pub fn get_mode_marker(&self) -> Option<char> {
+        if !self.on_disk {
+            return None;
+        }
+        let names = xattr::list(self.path(), !matches!(self.file_type, FilesType::Sym(_, _)));
 
-                Ok(user.name)
-            }
-            #[cfg(not(unix))]
-            {
-                Ok(String::new())
-            }
+        if cfg!(target_os = "macos") && !names.is_empty() {
+            return Some('@');
+        }
+        if names.iter().any(|name| name == "system.posix_acl_access" || name == "system.posix_acl_default") {
+            return Some('+');
+        }
+        names.iter().any(|name| name == "security.selinux").then_some('.')
+    }
+
+    // Every extended attribute with the size of its value, unreadable ones left out
+    pub fn get_xattr_sizes(&self) -> Vec<(String, usize)> {
+        if !self.on_disk {
+            return vec![];
+        }
+        let follow = !matches!(self.file_type, FilesType::Sym(_, _));
+        xattr::list(self.path(), follow)
+            .into_iter()
+            .filter_map(|name| xattr::size(self.path(), &name, follow).map(|size| (name, size)))
+            .collect()
+    }
+
+    // File capabilities of a regular file in getcap(8) form, empty without
+    // any and `?` when they can't be read or decoded
+    pub fn get_capabilities(&self) -> String {
+        const NAME: &str = "security.capability";
+        if !self.on_disk || !matches!(self.file_type, FilesType::File(_)) || !xattr::list(self.path(), true).iter().any(|name| name == NAME) {
+            return String::new();
+        }
+
+        xattr::get(self.path(), NAME, true)
+            .and_then(|value| caps::decode(&value))
+            .unwrap_or_else(|| String::from("?"))
+    }
+
+    // The SELinux security context, None without SELinux or a label
+    pub fn get_security_context(&self) -> Option<String> {
+        if !self.on_disk {
+            return None;
+        }
+        let value = xattr::get(self.path(), "security.selinux", !matches!(self.file_type, FilesType::Sym(_, _)))?;
+        Some(String::from_utf8_lossy(&value).trim_end_matches('\0').to_string())
+    }
+
+    // Major and minor number of block and character devices, shown instead of the size
+    pub fn get_device_numbers(&self) -> Option<(u64, u64)> {
+        if !matches!(self.file_type, FilesType::Block(_) | FilesType::Char(_)) {
+            return None;
+        }
+
+        Some(split_device(self.rdev))
+    }
+
+    // Comma separated like `ls -lO`, `-` without any
+    pub fn get_flags_str(&self) -> String {
+        let names: Vec<&str> = FLAG_NAMES
+            .iter()
+            .filter(|(bit, _)| self.flags & bit != 0)
+            .map(|(_, name)| *name)
+            .collect();
+
+        if names.is_empty() {
+            String::from("-")
         } else {
-            Ok(String::new())
+            names.join(",")
         }
     }
 
-    pub fn get_link_count(&self) -> u64 {
-        #[cfg(unix)]
-        {
-            match nix::sys::stat::lstat(self.path()) {
-                Err(_) => 0,
-                Ok(p) => p.st_nlink
-            }
+    // Major and minor number of the device the entry itself is stored on
+    pub fn get_storage_device(&self) -> (u64, u64) {
+        split_device(self.dev)
+    }
+
+}
+
+// Bits of `st_flags` by their chflags(1) names
+const FLAG_NAMES: [(u32, &str); 11] = [
+    (0x1, "nodump"),
+    (0x2, "uchg"),
+    (0x4, "uappnd"),
+    (0x8, "opaque"),
+    // UF_COMPRESSED on macOS, UF_NOUNLINK on FreeBSD
+    (0x10, if cfg!(target_os = "macos") { "compressed" } else { "uunlnk" }),
+    (0x8000, "hidden"),
+    (0x10000, "arch"),
+    (0x20000, "schg"),
+    (0x40000, "sappnd"),
+    (0x80000, if cfg!(target_os = "macos") { "restricted" } else { "snapshot" }),
+    (0x100000, "sunlnk")
+];
+
+#[cfg(target_os = "macos")]
+fn get_flags(meta: &fs::Metadata) -> u32 {
+    use std::os::macos::fs::MetadataExt;
+    meta.st_flags()
+}
+
+#[cfg(target_os = "freebsd")]
+fn get_flags(meta: &fs::Metadata) -> u32 {
+    use std::os::freebsd::fs::MetadataExt;
+    meta.st_flags()
+}
+
+// Linux keeps its file attributes behind an ioctl instead
+#[cfg(not(any(target_os = "macos", target_os = "freebsd")))]
+fn get_flags(_meta: &fs::Metadata) -> u32 {
+    0
+}
+
+fn split_device(dev: u64) -> (u64, u64) {
+    #[cfg(target_os = "linux")]
+    {
+        (nix::sys::stat::major(dev), nix::sys::stat::minor(dev))
+    }
+    // The BSD layout, also used by macOS
+    #[cfg(not(target_os = "linux"))]
+    {
+        ((dev >> 24) & 0xff, dev & 0xffffff)
+    }
+}
+
+#[cfg(unix)]
+fn unix_time(secs: i64, nsecs: i64) -> SystemTime {
+    let nsecs = std::time::Duration::from_nanos(nsecs as u64);
+    if secs >= 0 {
+        SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(secs as u64) + nsecs
+    } else {
+        SystemTime::UNIX_EPOCH - std::time::Duration::from_secs(secs.unsigned_abs()) + nsecs
+    }
+}
+
+// Like fnmatch with FNM_PERIOD, a leading dot has to be matched literally
+fn ignore_match(pattern: &str, name: &str) -> bool {
+    if name.starts_with('.') && !pattern.starts_with('.') && !pattern.starts_with("\\.") {
+        return false;
+    }
+
+    glob_match(pattern, name)
+}
+
+// With --glob or --iglob given, a name has to match at least one of them
+fn glob_filter(opts: &Opts, name: &OsStr) -> bool {
+    if opts.glob.is_empty() && opts.iglob.is_empty() {
+        return true;
+    }
+
+    let name = name.to_string_lossy();
+    let lower = name.to_lowercase();
+    opts.glob.iter().any(|pattern| glob_match(pattern, &name))
+        || opts.iglob.iter().any(|pattern| glob_match(pattern, &lower))
+}
+
+// The byte ranges of `name` matched by the first --glob or --iglob pattern it passes
+pub fn glob_spans(opts: &Opts, name: &str) -> Option<Vec<Range<usize>>> {
+    if let Some(spans) = opts.glob.iter().find_map(|pattern| glob_match_spans(pattern, name)) {
+        return Some(spans);
+    }
+
+    // Ranges into the lowercased name only carry over when lowercasing kept the offsets
+    let lower = name.to_lowercase();
+    if lower.len() != name.len() || lower.char_indices().any(|(i, _)| !name.is_char_boundary(i)) {
+        return None;
+    }
+    opts.iglob.iter().find_map(|pattern| glob_match_spans(pattern, &lower))
+}
+
+fn get_env_size(s: &str) -> Option<BlockSize> {
+    match std::env::var(s) {
+        Err(_) => None,
+        Ok(s) => BlockSize::parse(&s).ok()
+    }
+}
+
+// LS_BLOCK_SIZE and BLOCK_SIZE scale the size column as well, like GNU ls
+fn get_size_block_size() -> Option<&'static BlockSize> {
+    static CACHE: OnceLock<Option<BlockSize>> = OnceLock::new();
+
+    CACHE.get_or_init(|| get_env_size("LS_BLOCK_SIZE").or_else(|| get_env_size("BLOCK_SIZE"))).as_ref()
+}
+
+fn get_block_size() -> &'static BlockSize {
+    static CACHE: OnceLock<BlockSize> = OnceLock::new();
+
+    CACHE.get_or_init(|| block_size_from(|key| std::env::var(key).ok()))
+}
+
+// Precedence follows GNU ls: LS_BLOCK_SIZE, BLOCK_SIZE, BLOCKSIZE, then
+// POSIXLY_CORRECT whose presence alone selects 512 byte blocks.
+fn block_size_from(var: impl Fn(&str) -> Option<String>) -> BlockSize {
+    let size = |key: &str| var(key).and_then(|spec| BlockSize::parse(&spec).ok());
+
+    size("LS_BLOCK_SIZE")
+        .or_else(|| size("BLOCK_SIZE"))
+        .or_else(|| size("BLOCKSIZE"))
+        .or_else(|| var("POSIXLY_CORRECT").map(|_| BlockSize::Scaled(512, None, false)))
+        .unwrap_or(BlockSize::Scaled(1024, None, false))
+}
+
+// The size column stays in bytes unless -h, --si, --block-size or the
+// environment picked a unit. The options take precedence over the environment.
+pub fn format_size(bytes: u64, opts: &Opts) -> String {
+    let block_size = opts.block_size.as_ref().or_else(|| get_size_block_size());
+    let size = match block_size {
+        None => bytes.to_string(),
+        Some(block_size) => block_size.format(bytes)
+    };
+
+    // Human sizes are never grouped, they're at most three digits anyway
+    match &opts.size_grouping {
+        Some(separator) if !matches!(block_size, Some(BlockSize::Human | BlockSize::Si)) => {
+            let digits = size.chars().take_while(char::is_ascii_digit).count();
+            size::group_digits(&size[..digits], separator) + &size[digits..]
+        },
+        _ => size
+    }
+}
+
+// 512 byte blocks in units of the block size, rounded up like GNU ls
+// -k only overrides the environment and leaves the size column alone
+pub fn format_blocks(blocks: u64, opts: &Opts) -> String {
+    const KIBIBYTES: BlockSize = BlockSize::Scaled(1024, None, false);
+
+    opts.block_size
+        .as_ref()
+        .or(if opts.kibibytes { Some(&KIBIBYTES) } else { None })
+        .unwrap_or_else(|| get_block_size())
+        .format(blocks * 512)
+}
+
+// C/POSIX compares bytes. Otherwise approximates glibc's locale collation:
+// punctuation is ignored and case folded first, then lowercase sorts before
+// uppercase, with the raw bytes as the final tiebreak.
+pub fn collate_cmp(a: &str, b: &str, collation: &Collation) -> Ordering {
+    if let Collation::Bytes = collation {
+        return a.cmp(b);
+    }
+
+    fn letters(s: &str) -> impl Iterator<Item = char> + '_ {
+        s.chars().filter(|c| c.is_alphanumeric())
+    }
+
+    letters(a)
+        .flat_map(char::to_lowercase)
+        .cmp(letters(b).flat_map(char::to_lowercase))
+        .then_with(|| letters(a).map(char::is_uppercase).cmp(letters(b).map(char::is_uppercase)))
+        .then_with(|| a.cmp(b))
+}
+
+// Port of gnulib's filevercmp, used by `ls -v` and `sort -V`
+fn version_cmp(a: &str, b: &str) -> Ordering {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.is_empty() || b.is_empty() {
+        return a.len().cmp(&b.len());
+    }
+
+    // `.` sorts first, then `..`, then other dotfiles, then everything else
+    if a[0] == b'.' {
+        if b[0] != b'.' {
+            return Ordering::Less;
         }
-        #[cfg(not(unix))]
-        {
-            0
+
+        let (adot, bdot) = (a.len() == 1, b.len() == 1);
+        if adot || bdot {
+            return bdot.cmp(&adot);
+        }
+
+        let (adotdot, bdotdot) = (a == b"..", b == b"..");
+        if adotdot || bdotdot {
+            return bdotdot.cmp(&adotdot);
         }
+    } else if b[0] == b'.' {
+        return Ordering::Greater;
     }
 
+    let (aprefix, bprefix) = (version_prefix_len(a), version_prefix_len(b));
+    let result = version_rev_cmp(&a[..aprefix], &b[..bprefix]);
+    if result != Ordering::Equal || (aprefix == a.len() && bprefix == b.len()) {
+        result
+    } else {
+        version_rev_cmp(a, b)
+    }
 }
 
-fn get_block_size() -> i64 {
-    fn get_env_size(s: &str) -> Option<i64> {
-        match std::env::var(s) {
-            Err(_) => None,
-            Ok(s) => {
-                match s.parse::<i64>() {
-                    Err(_) => None,
-                    Ok(p) => Some(p)
-                }
+// Length of the name without its suffix matching `(\.[A-Za-z~][A-Za-z0-9~]*)*$`
+fn version_prefix_len(s: &[u8]) -> usize {
+    let mut prefix_len = 0;
+    let mut i = 0;
+
+    while i < s.len() {
+        i += 1;
+        prefix_len = i;
+
+        while i + 1 < s.len() && s[i] == b'.' && (s[i + 1].is_ascii_alphabetic() || s[i + 1] == b'~') {
+            i += 2;
+            while i < s.len() && (s[i].is_ascii_alphanumeric() || s[i] == b'~') {
+                i += 1;
+            }
+        }
+    }
+
+    prefix_len
+}
+
+fn version_rev_cmp(a: &[u8], b: &[u8]) -> Ordering {
+    fn order(c: Option<&u8>) -> i32 {
+        match c {
+            None => 0,
+            Some(c) if c.is_ascii_digit() => 0,
+            Some(c) if c.is_ascii_alphabetic() => *c as i32,
+            Some(b'~') => -1,
+            Some(c) => *c as i32 + 256
+        }
+    }
+
+    let is_digit = |s: &[u8], i: usize| s.get(i).is_some_and(|c| c.is_ascii_digit());
+    let (mut i, mut j) = (0, 0);
+
+    while i < a.len() || j < b.len() {
+        while (i < a.len() && !is_digit(a, i)) || (j < b.len() && !is_digit(b, j)) {
+            let (ac, bc) = (order(a.get(i)), order(b.get(j)));
+            if ac != bc {
+                return ac.cmp(&bc);
             }
+            i += 1;
+            j += 1;
+        }
+
+        while a.get(i) == Some(&b'0') {
+            i += 1;
+        }
+        while b.get(j) == Some(&b'0') {
+            j += 1;
+        }
+
+        let mut first_diff = Ordering::Equal;
+        while is_digit(a, i) && is_digit(b, j) {
+            if first_diff == Ordering::Equal {
+                first_diff = a[i].cmp(&b[j]);
+            }
+            i += 1;
+            j += 1;
+        }
+
+        if is_digit(a, i) {
+            return Ordering::Greater;
+        }
+        if is_digit(b, j) {
+            return Ordering::Less;
+        }
+        if first_diff != Ordering::Equal {
+            return first_diff;
         }
     }
 
-    get_env_size("LS_BLOCK_SIZE")
-        .or_else(|| get_env_size("BLOCK_SIZE"))
-        .or_else(|| get_env_size("BLOCKSIZE"))
-        .or_else(|| get_env_size("POSIXLY_CORRECT"))
-        .unwrap_or(1024)
+    Ordering::Equal
 }
 
 pub struct FilesList {
     pub entries: Vec<FilesEntry>,
-    pub dir: FilesEntry,
+    pub dir: Option<FilesEntry>,
     pub up_dir: Option<FilesEntry>,
-    pub blocks: i64 
+    // In 512 byte blocks like `FilesEntry::blocks`
+    pub blocks: u64,
+    pub hidden: usize,
+    // Subdirectory listings for -R and --tree, keyed by their index in `entries`
+    pub children: Vec<(usize, FilesList)>
 }
 
 impl FilesList {
     pub fn new(path: &PathBuf, opts: &Opts) -> Result<Self, Box<dyn Error>> {
+        Self::read(path, path, opts, &[])
+    }
+
+    // `label` is the path shown in the header, built from the operand as given
+    fn read(path: &PathBuf, label: &PathBuf, opts: &Opts, ancestors: &[PathBuf]) -> Result<Self, Box<dyn Error>> {
+        let path = &path.klong();
         let mut list: Vec<FilesEntry> = vec![];
-        let self_entry = FilesEntry::new_dir(&path.to_path_buf(), path.metadata()?);
+        let mut self_entry = FilesEntry::new_dir(&path.to_path_buf(), path.metadata()?);
+        self_entry.label = Some(label.as_os_str().to_owned());
 
-        let block_size = get_block_size();
-        let mut blocks: i64 = 0;
+        let mut blocks: u64 = 0;
+        let mut hidden: usize = 0;
 
         #[cfg(unix)]
         {
@@ -328,7 +798,7 @@ impl FilesList {
                 blocks += match nix::sys::stat::lstat(path) {
                     Err(_) => 0,
                     Ok(p) => {
-                        p.st_blocks
+                        p.st_blocks as u64
                     }
                 };
 
@@ -341,48 +811,120 @@ impl FilesList {
                 blocks += match nix::sys::stat::lstat(parent_path) {
                     Err(_) => 0,
                     Ok(p) => {
-                        p.st_blocks
+                        p.st_blocks as u64
                     }
                 };
             }
         }
 
         if path.is_dir() {
-            for entry in fs::read_dir(path)? {
-                let entry = entry?;
-                let meta = entry.metadata()?;
-                let path = entry.path();
-                match path.file_name().and_then(|p|p.to_str()) {
-                    None => (),
-                    Some(s) => {
-                        if let Some(c) = s.chars().nth(0) {
-                            if c == '.' && !opts.all_files {
-                                continue;
-                            }
-                        }
+            // Entries are joined onto the resolved directory so they don't need
+            // their own canonicalize, and stat'ed relative to its descriptor.
+            let base = path.canonicalize()?;
+            #[cfg(unix)]
+            let dir_handle = fs::File::open(&base).ok();
+            let git_ignore = if opts.git_ignore { GitIgnore::for_dir(&base) } else { None };
+            let git_status = if opts.git { GitStatus::for_dir(&base) } else { None };
+
+            // A single unreadable entry is reported and skipped, the rest is still
+            // listed. Under --strict the first one ends the whole listing.
+            let report = |name: &OsStr, e: &(dyn Error + 'static)| {
+                error::report(&format!("cannot access '{}'", label.join(name).kstr()), e);
+                error::check_strict()
+            };
+
+            for entry in fs::read_dir(&base)? {
+                let entry = match entry {
+                    Ok(entry) => entry,
+                    Err(e) => {
+                        error::report(&format!("reading directory '{}'", label.kstr()), &e);
+                        error::check_strict()?;
+                        continue;
                     }
+                };
+                let name = entry.file_name();
+                let s = name.to_string_lossy();
+                // Names skipped here are never stat'ed or descended into, so with -R or
+                // --tree an ignored directory prunes its whole subtree.
+                // -a does not bring backups or ignored names back, same as GNU ls
+                if opts.ignore_backups && s.ends_with('~') {
+                    continue;
+                }
+                if opts.ignore.iter().any(|pattern| ignore_match(pattern, &s)) {
+                    continue;
                 }
 
+                // --hide only applies, and so only prunes, without -a or -A
+                let show_all = opts.all_files || opts.almost_all;
+                if !show_all && (s.starts_with('.') || opts.hide.iter().any(|pattern| ignore_match(pattern, &s))) {
+                    hidden += 1;
+                    continue;
+                }
 
-                let files_entry: FilesEntry = if path.is_symlink() {
-                    FilesEntry::new_sym(&path.kabsolute()?, &std::fs::read_link(&path)?, meta)
-                } else if path.is_dir() {
-                    FilesEntry::new_dir(&path, meta)
-                } else {
-                    FilesEntry::new_file(&path, meta)
+                // The entry's own lstat data, so a dangling symlink is still described
+                let mut meta = match entry.metadata() {
+                    Ok(meta) => meta,
+                    Err(e) => {
+                        report(&name, &e)?;
+                        continue;
+                    }
                 };
 
-                #[cfg(unix)]
-                {
-                    blocks += match nix::sys::stat::lstat(&path) {
-                        Err(_) => 0,
-                        Ok(p) => {
-                            p.st_blocks
+                let path = base.join(&name);
+                if let Some(git_ignore) = &git_ignore {
+                    if git_ignore.is_ignored(&path, meta.is_dir()) {
+                        continue;
+                    }
+                }
+
+                // Directories are kept while recursing so matches further down are still found
+                let recursing = (opts.recursive || opts.tree) && meta.is_dir();
+                if !recursing && !glob_filter(opts, &name) {
+                    continue;
+                }
+
+                // With -L a broken link is reported and listed as the link itself
+                if opts.dereference && meta.file_type().is_symlink() {
+                    match fs::metadata(&path) {
+                        Ok(target) => meta = target,
+                        Err(e) => report(&name, &e)?
+                    }
+                }
+
+                let kind = meta.file_type();
+                let file_type = if kind.is_symlink() {
+                    #[cfg(unix)]
+                    let target = Self::read_entry_link(dir_handle.as_ref(), &name, &path);
+                    #[cfg(not(unix))]
+                    let target: Result<PathBuf, Box<dyn Error>> = fs::read_link(&path).map_err(Into::into);
+
+                    let target = match target {
+                        Ok(target) => target,
+                        Err(e) => {
+                            report(&name, e.as_ref())?;
+                            continue;
                         }
-                    }; 
+                    };
+
+                    FilesType::Sym(path, target)
+                } else if kind.is_dir() {
+                    FilesType::Dir(path)
+                } else {
+                    FilesType::other(path, &kind)
+                };
+
+                match opts.only {
+                    Only::All => (),
+                    Only::Dirs => if !file_type.targets_dir() { continue },
+                    Only::Files => if file_type.targets_dir() { continue }
                 }
 
-                list.push(files_entry);
+                let mut entry = FilesEntry::new(file_type, meta);
+                if let Some(git_status) = &git_status {
+                    entry.git = Some(git_status.get(entry.path(), matches!(entry.file_type, FilesType::Dir(_))));
+                }
+                blocks += entry.blocks;
+                list.push(entry);
             }
         }
 
@@ -390,21 +932,509 @@ impl FilesList {
 
         let mut result = Self {
             entries: list,
-            dir: self_entry,
+            dir: Some(self_entry),
             up_dir: up_entry,
-            blocks: (blocks * 512) / block_size
+            blocks,
+            hidden,
+            children: vec![]
         };
 
-        result.sort();
+        // -U keeps the order the entries were read in
+        if !matches!(opts.sort, SortMode::None) {
+            result.sort(opts);
+        }
+
+        if opts.recursive || opts.tree {
+            let mut ancestors = ancestors.to_vec();
+            ancestors.push(path.canonicalize()?);
+            result.read_children(label, opts, &ancestors)?;
+        }
 
         Ok(result)
     }
 
-    fn sort(&mut self) {
-        self.entries.sort_by_key(|key| {
-            let name = key.name().unwrap_or(".").to_lowercase();
-            name.replace(".", "")
-            //format!("{}{}", key.file_type.conditional_text(" ", ""), name)
-        });
+    // Symlinked directories are only followed with -L, where `ancestors`
+    // holds the resolved directories above so a link back up is not entered
+    fn read_children(&mut self, label: &Path, opts: &Opts, ancestors: &[PathBuf]) -> Result<(), KlsError> {
+        // The operand is the first ancestor and its entries are level 1
+        if opts.level.is_some_and(|level| ancestors.len() >= level) {
+            return Ok(());
+        }
+
+        // The listing never leaves the operand's device, so comparing with the parent is enough
+        let dev = self.dir.as_ref().map(|dir| dir.dev);
+        for (i, entry) in self.entries.iter().enumerate() {
+            if let FilesType::Dir(path) = &entry.file_type {
+                // A mount point is still listed, just not descended into
+                if opts.one_file_system && dev.is_some_and(|dev| dev != entry.dev) {
+                    continue;
+                }
+
+                let label = label.join(path.file_name().unwrap_or_default());
+                if path.canonicalize().is_ok_and(|real| ancestors.contains(&real)) {
+                    error::report(&format!("'{}'", label.kstr()), &KlsError::S(String::from("not listing already-listed directory")));
+                    error::check_strict()?;
+                    continue;
+                }
+
+                match Self::read(path, &label, opts, ancestors) {
+                    Ok(list) => self.children.push((i, list)),
+                    Err(e) => error::report(&format!("cannot open directory '{}'", label.kstr()), e.as_ref())
+                }
+                error::check_strict()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // The members of an archive for --archive, as if it was a directory.
+    // The total adds up the member sizes.
+    pub fn from_archive(path: &PathBuf, opts: &Opts) -> Result<Self, Box<dyn Error>> {
+        let entries = archive::read(path)?;
+        let blocks = entries.iter().map(|entry| entry.blocks).sum();
+        let mut result = Self {
+            entries,
+            dir: Some(FilesEntry::from_operand(path, true)?),
+            up_dir: None,
+            blocks,
+            hidden: 0,
+            children: vec![]
+        };
+
+        result.sort(opts);
+        Ok(result)
+    }
+
+    // A list of the operands themselves, printed without a header or total
+    pub fn from_operands(entries: Vec<FilesEntry>, opts: &Opts) -> Self {
+        let mut result = Self {
+            entries,
+            dir: None,
+            up_dir: None,
+            blocks: 0,
+            hidden: 0,
+            children: vec![]
+        };
+
+        result.sort(opts);
+        result
+    }
+
+    #[cfg(unix)]
+    fn read_entry_link(dir: Option<&fs::File>, name: &OsStr, path: &Path) -> Result<PathBuf, Box<dyn Error>> {
+        use std::os::fd::AsRawFd;
+
+        match dir {
+            Some(dir) => Ok(PathBuf::from(nix::fcntl::readlinkat(Some(dir.as_raw_fd()), name)?)),
+            None => Ok(fs::read_link(path)?)
+        }
+    }
+
+    // One sort key in its usual order: names and extensions ascending, times
+    // newest first and sizes largest first
+    fn compare(mode: &SortMode, a: &FilesEntry, b: &FilesEntry, opts: &Opts) -> Ordering {
+        match mode {
+            SortMode::Name => collate_cmp(&a.name_lossy(), &b.name_lossy(), &opts.collation),
+            SortMode::Time => b.time(&opts.time).cmp(&a.time(&opts.time)),
+            SortMode::Size => b.size.cmp(&a.size),
+            SortMode::Extension => a.extension().cmp(&b.extension()),
+            SortMode::Version => version_cmp(&a.name_lossy(), &b.name_lossy()),
+            SortMode::None | SortMode::Keys(_) => Ordering::Equal
+        }
+    }
+
+    fn sort(&mut self, opts: &Opts) {
+        // Single keys fall back to the name, a list of keys only to the ones given
+        let key_cmp = |a: &FilesEntry, b: &FilesEntry| match &opts.sort {
+            SortMode::Keys(keys) => keys.iter().fold(Ordering::Equal, |order, (mode, reversed)| {
+                order.then_with(|| {
+                    let order = Self::compare(mode, a, b, opts);
+                    if *reversed { order.reverse() } else { order }
+                })
+            }),
+            SortMode::Name | SortMode::Version | SortMode::None => Self::compare(&opts.sort, a, b, opts),
+            mode => Self::compare(mode, a, b, opts).then_with(|| Self::compare(&SortMode::Name, a, b, opts))
+        };
+
+        // Raw file name bytes break any remaining tie, so equal keys and
+        // non UTF-8 names never fall back to directory read order
+        if !matches!(opts.sort, SortMode::None) {
+            self.entries.sort_by(|a, b| {
+                key_cmp(a, b).then_with(|| a.path().file_name().cmp(&b.path().file_name()))
+            });
+        }
+
+        // `.` and `..` are added by the formatter so they stay on top
+        if opts.reverse {
+            self.entries.reverse();
+        }
+
+        // Stable, so each group keeps the order from above. Symlinks to
+        // directories are not resolved and stay with the files.
+        if opts.group_directories_first {
+            self.entries.sort_by_key(|entry| !matches!(entry.file_type, FilesType::Dir(_)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use crate::params::{Collation, Params};
+    use crate::testdir::TestDir;
+
+    // Ties on purpose: three entries of size 10, two of each extension and two of each age
+    fn fixture() -> FilesList {
+        let entry = |name: &str, size: u64, age: u64| FilesEntry::new_virtual(
+            FilesType::File(PathBuf::from("/fixture").join(name)),
+            0o644,
+            size,
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000 - age),
+            (0, 0));
+
+        FilesList {
+            entries: vec![
+                entry("b.txt", 10, 1),
+                entry("e.rs", 10, 2),
+                entry("a.rs", 10, 2),
+                entry("d.txt", 5, 1),
+                entry("c.rs", 20, 3)
+            ],
+            dir: None,
+            up_dir: None,
+            blocks: 0,
+            hidden: 0,
+            children: vec![]
+        }
+    }
+
+    fn sorted(args: &[&str]) -> Vec<String> {
+        let params = Params::from_args(args.iter().map(|arg| arg.to_string())).unwrap();
+        let mut list = fixture();
+        list.sort(&params.opts);
+        list.entries.iter().map(|entry| entry.name_lossy().into_owned()).collect()
+    }
+
+    #[test]
+    fn total_follows_human_sizes() {
+        let opts = |args: &[&str]| Params::from_args(args.iter().map(|arg| arg.to_string())).unwrap().opts;
+
+        // 12700 blocks of 512 bytes are 6502400 bytes, 6.2 MiB rounded up like `ls -lh`
+        assert_eq!(format_blocks(12700, &opts(&["-lh"])), "6.3M");
+        assert_eq!(format_blocks(12700, &opts(&["-l", "--si"])), "6.6M");
+        assert_eq!(format_blocks(12700, &opts(&["-lk"])), "6350");
+        assert_eq!(format_blocks(12700, &opts(&["-l", "--block-size=512"])), "12700");
+        assert_eq!(format_blocks(0, &opts(&["-lh"])), "0");
+
+        let dir = std::env::temp_dir().join(format!("kls-test-total-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for name in ["a", "b", "c"] {
+            fs::write(dir.join(name), vec![1; 1 << 20]).unwrap();
+        }
+        let list = FilesList::new(&dir, &opts(&["-lh"])).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(list.blocks >= 3 * 2048);
+        assert!(format_blocks(list.blocks, &opts(&["-lh"])).ends_with('M'));
+    }
+
+    #[test]
+    fn single_keys() {
+        assert_eq!(sorted(&[]), ["a.rs", "b.txt", "c.rs", "d.txt", "e.rs"]);
+        assert_eq!(sorted(&["-S"]), ["c.rs", "a.rs", "b.txt", "e.rs", "d.txt"]);
+        assert_eq!(sorted(&["--sort=size"]), sorted(&["-S"]));
+        assert_eq!(sorted(&["-t"]), ["b.txt", "d.txt", "a.rs", "e.rs", "c.rs"]);
+    }
+
+    #[test]
+    fn later_keys_break_ties() {
+        assert_eq!(sorted(&["--sort=size,-name"]), ["c.rs", "e.rs", "b.txt", "a.rs", "d.txt"]);
+        assert_eq!(sorted(&["--sort=ext,size"]), ["c.rs", "a.rs", "e.rs", "b.txt", "d.txt"]);
+        assert_eq!(sorted(&["--sort=ext,-size"]), ["a.rs", "e.rs", "c.rs", "d.txt", "b.txt"]);
+        assert_eq!(sorted(&["--sort=time,-ext,name"]), ["b.txt", "d.txt", "a.rs", "e.rs", "c.rs"]);
+    }
+
+    #[test]
+    fn reverse_flips_the_whole_chain() {
+        let mut forward = sorted(&["--sort=size,-name"]);
+        forward.reverse();
+        assert_eq!(sorted(&["-r", "--sort=size,-name"]), forward);
+    }
+
+    fn block_size(vars: &[(&str, &str)]) -> BlockSize {
+        block_size_from(|key| vars.iter().find(|(k, _)| *k == key).map(|(_, v)| v.to_string()))
+    }
+
+    #[test]
+    fn block_size_env_precedence() {
+        assert_eq!(block_size(&[]), BlockSize::Scaled(1024, None, false));
+        assert_eq!(block_size(&[("POSIXLY_CORRECT", "")]), BlockSize::Scaled(512, None, false));
+        assert_eq!(block_size(&[("POSIXLY_CORRECT", "1"), ("BLOCKSIZE", "2K")]), BlockSize::Scaled(2048, None, false));
+        assert_eq!(block_size(&[("BLOCKSIZE", "2K"), ("BLOCK_SIZE", "4K")]), BlockSize::Scaled(4096, None, false));
+        assert_eq!(block_size(&[("BLOCK_SIZE", "4K"), ("LS_BLOCK_SIZE", "1M")]), BlockSize::Scaled(1 << 20, None, false));
+        assert_eq!(block_size(&[("LS_BLOCK_SIZE", "human-readable")]), BlockSize::Human);
+    }
+
+    #[test]
+    fn invalid_block_size_env_falls_through() {
+        // Each variable with a value that doesn't parse, passing on to the next one
+        assert_eq!(block_size(&[("LS_BLOCK_SIZE", "bogus"), ("BLOCK_SIZE", "kB")]), BlockSize::Scaled(1000, Some(String::from("kB")), false));
+        assert_eq!(block_size(&[("BLOCK_SIZE", "12Q"), ("BLOCKSIZE", "2K")]), BlockSize::Scaled(2048, None, false));
+        assert_eq!(block_size(&[("BLOCKSIZE", "0"), ("POSIXLY_CORRECT", "")]), BlockSize::Scaled(512, None, false));
+        assert_eq!(block_size(&[("LS_BLOCK_SIZE", ""), ("BLOCK_SIZE", "-1"), ("BLOCKSIZE", "x")]), BlockSize::Scaled(1024, None, false));
+    }
+
+    // In ascending order, taken from gnulib's test-filevercmp
+    const VERSIONS: &[&str] = &[
+        "",
+        ".",
+        "..",
+        ".0",
+        ".9",
+        ".A",
+        ".Z",
+        ".a~",
+        ".a",
+        ".b~",
+        ".b",
+        ".z",
+        ".zz~",
+        ".zz",
+        ".zz.~1~",
+        ".zz.0",
+        "0",
+        "9",
+        "A",
+        "Z",
+        "a~",
+        "a",
+        "a.b~",
+        "a.b",
+        "a.bc~",
+        "a.bc",
+        "a+",
+        "a.",
+        "a..a",
+        "a.+",
+        "b~",
+        "b",
+        "gcc-c++-10.8.12-0.7rc2.fc9.tar.bz2",
+        "glibc-2-0.1.beta1.fc10.rpm",
+        "glibc-common-5-0.2.beta2.fc9.ebuild",
+        "glibc-common-5-0.2b.deb",
+        "glibc-common-11b.ebuild",
+        "glibc-common-11-0.6rc2.ebuild",
+        "libstdc++-0.5.8.11-0.7rc2.fc10.tar.gz",
+        "libstdc++-4a.fc8.tar.gz",
+        "libstdc++-4.10.4.20-0.7rc2.fc9.tar.gz",
+        "libstdc++-devel-3.fc8.ebuild",
+        "libstdc++-devel-3a.fc9.tar.gz",
+        "libstdc++-devel-8.fc8.deb",
+        "libstdc++-devel-8.6.2-0.4b.fc8",
+        "nss_ldap-1-0.2b.fc9.tar.bz2",
+        "nss_ldap-1-0.6rc2.fc8.tar.gz",
+        "nss_ldap-1.0-0.1a.tar.gz",
+        "nss_ldap-10beta1.fc8.tar.gz",
+        "nss_ldap-10.11.8.6.20040204cvs.fc10.ebuild",
+        "z",
+        "zz~",
+        "zz",
+        "zz.~1~",
+        "zz.0",
+        "zz.0.txt",
+        "#.b#"
+    ];
+
+    #[test]
+    fn version_sort_mixed_alphanumeric_segments() {
+        for (i, a) in VERSIONS.iter().enumerate() {
+            for (j, b) in VERSIONS.iter().enumerate() {
+                assert_eq!(version_cmp(a, b), i.cmp(&j), "comparing {:?} with {:?}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn version_sort_file2_file10_file100() {
+        assert_eq!(version_cmp("file2", "file10"), Ordering::Less);
+        assert_eq!(version_cmp("file10", "file100"), Ordering::Less);
+        assert_eq!(version_cmp("file100", "file2"), Ordering::Greater);
+        assert_eq!(version_cmp("1.0~rc1", "1.0"), Ordering::Less);
+    }
+
+    #[test]
+    fn version_sort_1_2_9_before_1_2_10() {
+        assert_eq!(version_cmp("1.2.9", "1.2.10"), Ordering::Less);
+        assert_eq!(version_cmp("v1.9.txt", "v1.10.txt"), Ordering::Less);
+    }
+
+    #[test]
+    fn version_sort_leading_zeros_a01_a1() {
+        // Digit runs compare by value, the raw name breaks the tie when sorting
+        assert_eq!(version_cmp("a01", "a1"), Ordering::Equal);
+        assert_eq!(version_cmp("a007", "a7"), Ordering::Equal);
+        assert_eq!(version_cmp("a01", "a2"), Ordering::Less);
+    }
+
+    #[test]
+    fn version_sort_names_without_digits() {
+        assert_eq!(version_cmp("alpha", "beta"), Ordering::Less);
+        assert_eq!(version_cmp("beta", "alpha"), Ordering::Greater);
+        assert_eq!(version_cmp("same", "same"), Ordering::Equal);
+    }
+
+    fn params(args: &[&str]) -> Params {
+        Params::from_args(args.iter().map(|arg| arg.to_string())).unwrap()
+    }
+
+    fn names(list: &FilesList) -> Vec<String> {
+        list.entries.iter().map(|entry| entry.name_lossy().into_owned()).collect()
+    }
+
+    fn listed(dir: &TestDir, args: &[&str]) -> Vec<String> {
+        names(&FilesList::new(dir.path(), &params(args).opts).unwrap())
+    }
+
+    #[test]
+    fn equal_keys_fall_back_to_the_name() {
+        let dir = TestDir::new();
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        for name in ["c.txt", "a.txt", "d.txt", "b.txt"] {
+            fs::File::create(dir.file(name, b"same")).unwrap().set_modified(mtime).unwrap();
+        }
+
+        let expected = ["a.txt", "b.txt", "c.txt", "d.txt"];
+        for args in [&[][..], &["-S"], &["-t"], &["-X"], &["-v"], &["--sort=size,time"]] {
+            assert_eq!(listed(&dir, args), expected, "sorted with {:?}", args);
+        }
+        let mut reversed = expected;
+        reversed.reverse();
+        assert_eq!(listed(&dir, &["-tr"]), reversed);
+    }
+
+    #[test]
+    fn names_equal_under_collation_fall_back_to_bytes() {
+        let dir = TestDir::new();
+        for name in ["ab", "a_b", "a.b", "A.b"] {
+            dir.file(name, b"");
+        }
+
+        let mut opts = params(&[]).opts;
+        opts.collation = Collation::Locale;
+        assert_eq!(names(&FilesList::new(dir.path(), &opts).unwrap()), ["a.b", "a_b", "ab", "A.b"]);
+        opts.collation = Collation::Bytes;
+        assert_eq!(names(&FilesList::new(dir.path(), &opts).unwrap()), ["A.b", "a.b", "a_b", "ab"]);
+    }
+
+    #[test]
+    fn hide_is_overridden_by_all_but_ignore_is_not() {
+        let dir = TestDir::new();
+        for name in [".dot", "keep.txt", "build.o", "notes.tmp"] {
+            dir.file(name, b"");
+        }
+
+        let filters = ["--ignore=*.o", "--hide=*.tmp"];
+        let list = FilesList::new(dir.path(), &params(&filters).opts).unwrap();
+        assert_eq!(names(&list), ["keep.txt"]);
+        assert_eq!(list.hidden, 2);
+
+        let all = [".dot", "keep.txt", "notes.tmp"];
+        assert_eq!(listed(&dir, &["-a", "--ignore=*.o", "--hide=*.tmp"]), all);
+        assert_eq!(listed(&dir, &["--ignore=*.o", "--hide=*.tmp", "-A"]), all);
+        assert_eq!(listed(&dir, &["-I", "*.o", "-I", "*.tmp", "-a"]), [".dot", "keep.txt"]);
+    }
+
+    #[test]
+    fn patterns_only_match_a_leading_dot_literally() {
+        let dir = TestDir::new();
+        for name in [".hidden.tmp", "shown.tmp", "other"] {
+            dir.file(name, b"");
+        }
+
+        assert_eq!(listed(&dir, &["-a", "--ignore=*.tmp"]), [".hidden.tmp", "other"]);
+        assert_eq!(listed(&dir, &["-a", "--ignore=.*"]), ["other", "shown.tmp"]);
+    }
+
+    // How many directory levels below the operand were read
+    fn depth(list: &FilesList) -> usize {
+        list.children.iter().map(|(_, child)| depth(child) + 1).max().unwrap_or(0)
+    }
+
+    #[test]
+    fn level_limits_recursion() {
+        let dir = TestDir::new();
+        dir.dir("a/b/c/d");
+        dir.file("a/b/c/d/leaf", b"");
+
+        let read = |args: &[&str]| FilesList::new(dir.path(), &params(args).opts).unwrap();
+        assert_eq!(depth(&read(&["-R"])), 4);
+        assert_eq!(depth(&read(&["-R", "--level=0"])), 0);
+        assert_eq!(depth(&read(&["-R", "--level=1"])), 0);
+        assert_eq!(names(&read(&["-R", "--level=1"])), ["a"]);
+        assert_eq!(depth(&read(&["-R", "--level", "3"])), 2);
+        assert_eq!(depth(&read(&["--tree", "--level=100"])), 4);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn dangling_links_are_listed_with_the_rest() {
+        let dir = TestDir::new();
+        dir.file("good", b"content");
+        dir.symlink("nowhere", "dangling");
+
+        for args in [&["-l"][..], &["-lL"]] {
+            let list = FilesList::new(dir.path(), &params(args).opts).unwrap();
+            assert_eq!(names(&list), ["dangling", "good"], "listed with {:?}", args);
+            assert!(matches!(list.entries[0].file_type, FilesType::Sym(..)));
+            assert!(matches!(list.entries[1].file_type, FilesType::File(_)));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_describe_themselves_unless_followed() {
+        let dir = TestDir::new();
+        fs::File::create(dir.file("big", b"")).unwrap().set_len(10 << 20).unwrap();
+        dir.symlink("big", "link");
+
+        // Like `ls -l`: the link's own mode and the length of the target path
+        let list = FilesList::new(dir.path(), &params(&["-l"]).opts).unwrap();
+        let link = &list.entries[1];
+        assert_eq!(link.size, 3);
+        assert!(link.get_mode_str().starts_with('l'));
+
+        let list = FilesList::new(dir.path(), &params(&["-lL"]).opts).unwrap();
+        let link = &list.entries[1];
+        assert_eq!(link.size, 10 << 20);
+        assert!(link.get_mode_str().starts_with("-rw"));
+    }
+
+    // Nested well past MAX_PATH, listed from an operand without the `\\?\` prefix
+    #[cfg(windows)]
+    #[test]
+    fn lists_paths_beyond_max_path() {
+        let root = std::env::temp_dir().join(format!("kls-test-long-{}", std::process::id()));
+        let mut deep = root.klong();
+        for i in 0..5 {
+            deep.push(format!("{:0>60}", i));
+        }
+        fs::create_dir_all(&deep).unwrap();
+        fs::write(deep.join("leaf.txt"), b"leaf").unwrap();
+
+        let params = Params::from_args(["-R".to_string()]).unwrap();
+        let mut list = &FilesList::new(&root, &params.opts).unwrap();
+        for _ in 0..5 {
+            list = &list.children[0].1;
+        }
+
+        let leaf = &list.entries[0];
+        assert_eq!(leaf.name_lossy(), "leaf.txt");
+        assert!(leaf.path().kstr().len() > 300);
+        assert!(!leaf.path().kstr().starts_with(r"\\?\"));
+        let label = PathBuf::from(list.dir.as_ref().unwrap().label.clone().unwrap());
+        assert!(label.starts_with(&root) && !label.kstr().starts_with(r"\\?\"));
+
+        fs::remove_dir_all(root.klong()).unwrap();
     }
 }
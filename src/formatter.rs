@@ -1,84 +1,698 @@
-use std::os::unix::fs::MetadataExt;
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::thread;
+use std::time::{Duration, SystemTime};
 
+use chrono::{DateTime, Local};
 use colored::{ColoredString, Colorize};
 
 use crate::files::{self, FilesType};
-use crate::params::Opts;
-use crate::utils::{PathUtil, StrUtil};
-use crate::colors::compute_color_for;
 
-pub fn output(data: Vec<files::FilesList>, opts: &Opts) {
-    let mut first = true;
+use crate::params::{Checksum, Column, GroupBy, IndicatorStyle, Layout, OctalPermissions, Opts, TimeField, TimeStyle};
+use crate::utils::{self, PathUtil};
+use crate::colors::{compute_color_for, compute_type_color};
+use crate::error::{self, KlsError};
+use crate::checksum;
+use crate::mime;
+use crate::quote::quote;
+use crate::size::BlockSize;
+
+// Depth first, so each -R subdirectory follows its parent like in `ls -R`
+fn flatten<'a>(list: &'a files::FilesList, out: &mut Vec<&'a files::FilesList>) {
+    out.push(list);
+    for (_, child) in &list.children {
+        flatten(child, out);
+    }
+}
+
+// The `dir:` line above a directory's listing. As with GNU ls it depends only on
+// the number of operands and -R, never on the layout or stdout being a terminal
+fn dir_heading(list: &files::FilesList, operands: usize, opts: &Opts) -> Option<String> {
+    list.dir
+        .as_ref()
+        .filter(|_| opts.dir_headers && (operands > 1 || opts.recursive))
+        .map(|dir| format!("{}:", quote(dir.name().unwrap(), opts)))
+}
+
+pub fn output(data: Vec<files::FilesList>, opts: &Opts) -> Result<(), KlsError> {
+    if opts.tree {
+        return output_tree(&data, opts);
+    }
+
+    let mut lists: Vec<&files::FilesList> = vec![];
     for list in &data {
-        if !first {
-            println!("");
+        flatten(list, &mut lists);
+    }
+
+    let mut first = true;
+    for list in &lists {
+        if !first && opts.dir_headers {
+            println!();
         }
 
-        if data.len() > 1 {
-            println!("{}:", list.dir.name().unwrap());
+        if let Some(heading) = dir_heading(list, data.len(), opts) {
+            println!("{}", heading);
         }
 
         if opts.long_format {
-            output_one_list(list, opts);
+            output_one_list(list, opts)?;
         } else {
-            output_short_format(list, opts);
+            output_short_format(list, opts)?;
+        }
+
+        if opts.hidden_hint && list.hidden > 0 {
+            println!("{}", format!("(+{} hidden — use -a to show)", list.hidden).dimmed());
         }
 
         first = false;
     }
+
+    Ok(())
+}
+
+// A row of the tree: its branch prefix and entry, with the first row of each
+// operand after the first getting a blank line above
+type TreeRow = (bool, String, FormattedEntry);
+
+fn output_tree(data: &[files::FilesList], opts: &Opts) -> Result<(), KlsError> {
+    let mut rows: Vec<TreeRow> = vec![];
+    for (i, list) in data.iter().enumerate() {
+        let start = rows.len();
+        match &list.dir {
+            Some(dir) => {
+                rows.push((false, String::new(), FormattedEntry::new(dir, dir.name().unwrap_or(OsStr::new(".")), opts)));
+                get_tree_rows(list, "", opts, &mut rows);
+            },
+            None => {
+                for entry in &list.entries {
+                    if let Some(name) = entry.name() {
+                        rows.push((false, String::new(), FormattedEntry::new(entry, name, opts)));
+                    }
+                }
+            }
+        }
+        if let Some(row) = rows.get_mut(start).filter(|_| i > 0) {
+            row.0 = true;
+        }
+    }
+    error::check_strict()?;
+
+    if !opts.long_format {
+        for (gap, prefix, entry) in rows {
+            if gap {
+                println!();
+            }
+            println!("{}{}", prefix, entry.get_colored_name(opts));
+        }
+        return Ok(());
+    }
+
+    // Padded over the whole tree so the columns line up at every depth, with the
+    // indented name always last
+    let (heads, entries): (Vec<(bool, String)>, Vec<FormattedEntry>) = rows
+        .into_iter()
+        .map(|(gap, prefix, entry)| ((gap, prefix), entry))
+        .unzip();
+    let mut columns = Column::for_entries(&entries, opts);
+    columns.retain(|column| *column != Column::Name);
+    columns.push(Column::Name);
+
+    for ((gap, prefix), mut entry) in heads.into_iter().zip(FormattedEntry::pad(entries, opts)) {
+        if gap {
+            println!();
+        }
+        entry.colored_name = prefix + &entry.colored_name;
+        println!("{}", entry.get_long_line(&columns));
+    }
+
+    Ok(())
+}
+
+fn get_tree_rows(list: &files::FilesList, prefix: &str, opts: &Opts, rows: &mut Vec<TreeRow>) {
+    let mut children = list.children.iter().peekable();
+    for (i, entry) in list.entries.iter().enumerate() {
+        let Some(name) = entry.name() else {
+            continue;
+        };
+
+        let last = i + 1 == list.entries.len();
+        let branch = if last { "└── " } else { "├── " };
+        rows.push((false, format!("{}{}", prefix, branch), FormattedEntry::new(entry, name, opts)));
+
+        if let Some((_, child)) = children.next_if(|(index, _)| *index == i) {
+            let indent = if last { "    " } else { "│   " };
+            get_tree_rows(child, &format!("{}{}", prefix, indent), opts, rows);
+        }
+    }
 }
 
 fn get_formatted_list(list: &files::FilesList, opts: &Opts) -> Vec<FormattedEntry> {
     let mut format_list: Vec<FormattedEntry> = vec![];
-    if opts.all_files {
-        format_list.push(FormattedEntry::new(&list.dir, "."));
-        if let Some(dir) = &list.up_dir {
-            format_list.push(FormattedEntry::new(dir, ".."));
+    if let Some(dir) = list.dir.as_ref().filter(|_| opts.all_files) {
+        format_list.push(FormattedEntry::new(dir, OsStr::new("."), opts));
+        if let Some(up_dir) = &list.up_dir {
+            format_list.push(FormattedEntry::new(up_dir, OsStr::new(".."), opts));
         } else {
-            format_list.push(FormattedEntry::new(&list.dir, ".."));
+            format_list.push(FormattedEntry::new(dir, OsStr::new(".."), opts));
         }
     }
 
+    let offset = format_list.len();
     for entry in &list.entries {
-        if let Some(file_name) = entry.name() {
-            format_list.push(FormattedEntry::new(&entry, file_name));
-        }
+        format_list.push(FormattedEntry::new(entry, entry.name().unwrap_or_default(), opts));
+    }
+    if opts.hardlinks {
+        mark_hardlinks(&list.entries, &mut format_list[offset..]);
+    }
+    // Hashing reads every file, so only when the column is shown
+    let shows_checksum = opts.long_format && Column::visible(opts).contains(&Column::Checksum);
+    if let Some(kind) = opts.checksum.as_ref().filter(|_| shows_checksum) {
+        add_checksums(&list.entries, &mut format_list[offset..], kind, opts.checksum_limit);
     }
 
     format_list
 }
 
-pub fn output_one_list(list: &files::FilesList, opts: &Opts) {
-    let format_list = get_formatted_list(list, opts);
-    let format_list = FormattedEntry::pad(format_list, opts);
+// Hashes the regular files on as many threads as there are cores, each taking
+// the next file when done. Unreadable files get `?` and files over the limit
+// `skipped`, with the listing going on either way.
+fn add_checksums(entries: &[files::FilesEntry], formatted: &mut [FormattedEntry], kind: &Checksum, limit: Option<u64>) {
+    let get_checksum = |entry: &files::FilesEntry| match &entry.file_type {
+        FilesType::File(_) if !entry.on_disk => String::from("-"),
+        FilesType::File(_) if limit.is_some_and(|limit| entry.size > limit) => String::from("skipped"),
+        FilesType::File(path) => checksum::file(path, kind).unwrap_or_else(|_| String::from("?")),
+        _ => String::from("-")
+    };
+
+    let threads = thread::available_parallelism().map_or(1, |n| n.get()).min(entries.len());
+    let next = AtomicUsize::new(0);
+    let checksums: Vec<(usize, String)> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|_| scope.spawn(|| {
+                let mut done = vec![];
+                loop {
+                    let i = next.fetch_add(1, AtomicOrdering::Relaxed);
+                    let Some(entry) = entries.get(i) else {
+                        break done;
+                    };
+                    done.push((i, get_checksum(entry)));
+                }
+            }))
+            .collect();
+
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().unwrap_or_default())
+            .collect()
+    });
 
-    println!("total {}", list.blocks);
-    for entry in format_list {
-        println!("{} {} {} {} {} {} {}", entry.mode, entry.links, entry.user, entry.group, entry.size, entry.modified, entry.name);
+    for (i, checksum) in checksums {
+        formatted[i].checksum = checksum;
     }
 }
 
-fn output_short_format(list: &files::FilesList, opts: &Opts) {
-    let format_list = get_formatted_list(list, opts);
+// Notes the other names of each file whose inode shows up more than once
+fn mark_hardlinks(entries: &[files::FilesEntry], formatted: &mut [FormattedEntry]) {
+    let mut inodes: HashMap<(u64, u64), Vec<usize>> = HashMap::new();
+    for (i, entry) in entries.iter().enumerate() {
+        // Without inode numbers there's nothing to compare
+        if entry.inode != 0 && !matches!(entry.file_type, FilesType::Dir(_)) {
+            inodes.entry((entry.dev, entry.inode)).or_default().push(i);
+        }
+    }
 
-    let mut first = true;
-    for entry in format_list {
+    for indices in inodes.values().filter(|indices| indices.len() > 1) {
+        for &i in indices {
+            let others: Vec<&str> = indices
+                .iter()
+                .filter(|&&j| j != i)
+                .map(|&j| formatted[j].name.as_str())
+                .collect();
+            formatted[i].hardlinks = others.join(", ");
+        }
+    }
+}
+
+fn entries_differ(a: &files::FilesEntry, b: &files::FilesEntry) -> bool {
+    match (&a.file_type, &b.file_type) {
+        (FilesType::Dir(_), FilesType::Dir(_)) => false,
+        (FilesType::File(_), FilesType::File(_)) => a.size != b.size || a.mtime != b.mtime,
+        (FilesType::Sym(_, a), FilesType::Sym(_, b)) => a != b,
+        (FilesType::Block(_), FilesType::Block(_)) | (FilesType::Char(_), FilesType::Char(_)) => {
+            a.get_device_numbers() != b.get_device_numbers()
+        },
+        _ => true
+    }
+}
+
+// Pairs the entries of two listings up by name, going on into directories on
+// both sides that were read with -R. Names are relative to the two roots.
+fn diff_lists(a: &files::FilesList, b: &files::FilesList, prefix: &Path, opts: &Opts, rows: &mut Vec<(&'static str, FormattedEntry)>) {
+    type Side<'a> = Option<(usize, &'a files::FilesEntry)>;
+
+    let mut names: BTreeMap<&OsStr, (Side, Side)> = BTreeMap::new();
+    for (i, entry) in a.entries.iter().enumerate() {
+        if let Some(name) = entry.name() {
+            names.entry(name).or_default().0 = Some((i, entry));
+        }
+    }
+    for (i, entry) in b.entries.iter().enumerate() {
+        if let Some(name) = entry.name() {
+            names.entry(name).or_default().1 = Some((i, entry));
+        }
+    }
+
+    fn child(list: &files::FilesList, i: usize) -> Option<&files::FilesList> {
+        list.children.iter().find(|(j, _)| *j == i).map(|(_, child)| child)
+    }
+
+    for (name, pair) in names {
+        let path = prefix.join(name);
+        match pair {
+            (Some((_, a)), None) => rows.push(("<", FormattedEntry::new(a, path.as_os_str(), opts))),
+            (None, Some((_, b))) => rows.push((">", FormattedEntry::new(b, path.as_os_str(), opts))),
+            (Some((i, a_entry)), Some((j, b_entry))) => {
+                if entries_differ(a_entry, b_entry) {
+                    rows.push(("≠", FormattedEntry::new(a_entry, path.as_os_str(), opts)));
+                    rows.push((" ", FormattedEntry::new(b_entry, path.as_os_str(), opts)));
+                }
+                if let (Some(a), Some(b)) = (child(a, i), child(b, j)) {
+                    diff_lists(a, b, &path, opts, rows);
+                }
+            },
+            (None, None) => ()
+        }
+    }
+}
+
+// Prints entries only in `a` (<), only in `b` (>) and in both but differing (≠).
+// Returns whether any difference was found.
+pub fn output_diff(a: &files::FilesList, b: &files::FilesList, opts: &Opts) -> Result<bool, KlsError> {
+    let mut rows: Vec<(&str, FormattedEntry)> = vec![];
+    diff_lists(a, b, Path::new(""), opts, &mut rows);
+
+    let (markers, entries): (Vec<&str>, Vec<FormattedEntry>) = rows.into_iter().unzip();
+    let columns = Column::for_entries(&entries, opts);
+    let entries = if opts.long_format {
+        FormattedEntry::pad(entries, opts)
+    } else {
+        entries
+    };
+    error::check_strict()?;
+
+    for (marker, entry) in markers.iter().zip(&entries) {
+        if opts.long_format {
+            println!("{} {}", marker, entry.get_long_line(&columns));
+        } else if *marker != " " {
+            println!("{} {}", marker, entry.get_colored_name(opts));
+        }
+    }
+
+    Ok(!entries.is_empty())
+}
+
+// --stat, a labeled block per operand instead of a table
+pub fn output_stat(entries: &[files::FilesEntry], opts: &Opts) -> Result<(), KlsError> {
+    let blocks: Vec<Vec<(&str, String)>> = entries.iter().map(|entry| get_stat_lines(entry, opts)).collect();
+    error::check_strict()?;
+
+    let width = blocks.iter().flatten().map(|(label, _)| label.len()).max().unwrap_or(0);
+    for (i, lines) in blocks.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+
+        for (label, value) in lines {
+            println!("{} {}", format!("{:>width$}:", label).dimmed(), value);
+        }
+    }
+
+    Ok(())
+}
+
+fn get_stat_lines(entry: &files::FilesEntry, opts: &Opts) -> Vec<(&'static str, String)> {
+    const FULL: TimeStyle = TimeStyle::FullIso;
+    let name = entry.name().unwrap_or_default();
+    let with_id = |name: Result<String, String>, id: u32| match name {
+        Ok(name) => format!("{} ({})", name, id),
+        Err(_) => id.to_string()
+    };
+    let time = |time: Option<SystemTime>| time.map_or_else(|| String::from("-"), |time| format_time(time, SystemTime::now(), &FULL));
+
+    let mut lines = vec![("File", FormattedEntry::new(entry, name, opts).get_colored_name(opts))];
+    lines.push(("Type", String::from(match entry.file_type {
+        FilesType::File(_) => "regular file",
+        FilesType::Dir(_) => "directory",
+        FilesType::Sym(_, _) => "symbolic link",
+        FilesType::Block(_) => "block device",
+        FilesType::Char(_) => "character device",
+        FilesType::Fifo(_) => "fifo",
+        FilesType::Socket(_) => "socket"
+    })));
+    if let FilesType::Sym(link, target) = &entry.file_type {
+        lines.push(("Target", get_symlink_chain(link, target, opts)));
+    }
+    lines.push(("Mode", format!("{} ({:04o})", entry.get_mode_str(), entry.get_permission_bits())));
+    lines.push(("Owner", with_id(entry.get_user_str(), entry.uid)));
+    lines.push(("Group", with_id(entry.get_group_str(), entry.gid)));
+    match entry.get_device_numbers() {
+        Some((major, minor)) => lines.push(("Device type", format!("{}, {}", major, minor))),
+        None => lines.push(("Size", format!("{} bytes ({})", entry.size, BlockSize::Human.format(entry.size))))
+    }
+    lines.push(("Blocks", format!("{} ({} allocated)", entry.blocks, BlockSize::Human.format(entry.blocks * 512))));
+    lines.push(("Inode", entry.inode.to_string()));
+    let (major, minor) = entry.get_storage_device();
+    lines.push(("Device", format!("{}, {}", major, minor)));
+    lines.push(("Links", entry.get_link_count().to_string()));
+    lines.push(("Access", time(entry.time(&TimeField::Accessed))));
+    lines.push(("Modify", time(entry.time(&TimeField::Modified))));
+    lines.push(("Change", time(entry.time(&TimeField::Changed))));
+    lines.push(("Birth", time(entry.time(&TimeField::Birth))));
+    lines
+}
+
+// Every hop of a symlink chain as written, noting where it breaks off
+fn get_symlink_chain(link: &Path, target: &Path, opts: &Opts) -> String {
+    const MAX_HOPS: usize = 40;
+
+    let mut chain = vec![quote(target.as_os_str(), opts)];
+    let mut visited: HashSet<PathBuf> = HashSet::new();
+    let mut path = link.parent().unwrap_or(Path::new("/")).join(target);
+    for _ in 0..MAX_HOPS {
+        match path.symlink_metadata() {
+            Err(_) => return chain.join(" -> ") + " (missing)",
+            Ok(meta) if !meta.file_type().is_symlink() => return chain.join(" -> "),
+            Ok(_) => ()
+        }
+        if !visited.insert(path.clone()) {
+            break;
+        }
+
+        match std::fs::read_link(&path) {
+            Err(_) => break,
+            Ok(next) => {
+                chain.push(quote(next.as_os_str(), opts));
+                path = path.parent().unwrap_or(Path::new("/")).join(next);
+            }
+        }
+    }
+
+    chain.join(" -> ") + " (loop)"
+}
+
+fn get_sections(list: Vec<FormattedEntry>, opts: &Opts) -> Vec<(Option<&'static str>, Vec<FormattedEntry>)> {
+    match opts.group_by {
+        GroupBy::None => vec![(None, list)],
+        GroupBy::Type => {
+            let mut sections = [("Directories", vec![]), ("Files", vec![]), ("Symlinks", vec![]), ("Special files", vec![])];
+            for entry in list {
+                let index = match entry.kind {
+                    FormattedFile::Dir => 0,
+                    FormattedFile::File => 1,
+                    FormattedFile::Sym => 2,
+                    FormattedFile::Special => 3
+                };
+                sections[index].1.push(entry);
+            }
+
+            sections
+                .into_iter()
+                .filter(|(_, entries)| !entries.is_empty())
+                .map(|(title, entries)| (Some(title), entries))
+                .collect()
+        }
+    }
+}
+
+fn print_section_title(title: Option<&str>, first: bool) {
+    if let Some(title) = title {
         if !first {
-            print!("  ");
+            println!();
         }
+        println!("{}", title.bold().underline());
+    }
+}
 
-        print!("{}", entry.get_colored_name(opts));
-        
-        first = false;
+pub fn output_one_list(list: &files::FilesList, opts: &Opts) -> Result<(), KlsError> {
+    let mut format_list = get_formatted_list(list, opts);
+    let columns = Column::for_entries(&format_list, opts);
+
+    // The header goes through `pad` with the entries so its titles widen the columns too
+    let show_header = opts.header && !format_list.is_empty();
+    if show_header {
+        format_list.insert(0, FormattedEntry::header(opts));
+    }
+    let mut format_list = FormattedEntry::pad(format_list, opts);
+    let header = if show_header { Some(format_list.remove(0)) } else { None };
+    error::check_strict()?;
+
+    // Only listed directories have a total, not -d or file operands
+    if opts.show_total && opts.dir_headers && list.dir.is_some() {
+        println!("total {}", files::format_blocks(list.blocks, opts));
+    }
+    if let Some(mut header) = header {
+        header.colored_name = String::from("Name");
+        println!("{}", header.get_long_line(&columns).dimmed().underline());
+    }
+    for (i, (title, entries)) in get_sections(format_list, opts).into_iter().enumerate() {
+        print_section_title(title, i == 0);
+        // The xattrs under each row leave no room for a grid
+        if opts.grid && !opts.xattr_detail {
+            let rows: Vec<Cell> = entries
+                .iter()
+                .map(|entry| entry.get_long_line(&columns))
+                .map(|line| {
+                    let width = utils::display_width(&line);
+                    (line, width)
+                })
+                .collect();
+            for line in get_grid_lines(&rows, line_width(opts), false, opts.tab_size) {
+                println!("{}", line);
+            }
+            continue;
+        }
+        for entry in entries {
+            println!("{}", entry.get_long_line(&columns));
+            for (name, size) in &entry.xattrs {
+                println!("\t{}\t{:>4}", name, size);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// A name ready to print and its printed width, which colors don't count towards
+type Cell = (String, usize);
+
+fn output_short_format(list: &files::FilesList, opts: &Opts) -> Result<(), KlsError> {
+    let format_list = get_formatted_list(list, opts);
+    let inode_width = format_list.iter().map(|entry| entry.inode.len()).max().unwrap_or(0);
+    let blocks_width = format_list.iter().map(|entry| entry.blocks.len()).max().unwrap_or(0);
+    let context_width = format_list.iter().map(|entry| utils::display_width(&entry.context)).max().unwrap_or(0);
+    let get_name = |entry: &FormattedEntry| {
+        let prefix_width = [(opts.inode, inode_width), (opts.size_blocks, blocks_width), (opts.context, context_width)]
+            .iter()
+            .filter(|(shown, _)| *shown)
+            .map(|(_, width)| width + 1)
+            .sum::<usize>();
+        let mut name = String::new();
+        if opts.inode {
+            name += &format!("{:>width$} ", entry.inode, width = inode_width);
+        }
+        if opts.size_blocks {
+            name += &format!("{:>width$} ", entry.blocks, width = blocks_width);
+        }
+        if opts.context {
+            name += &format!("{:>width$} ", entry.context, width = context_width);
+        }
+        (name + &entry.get_colored_name(opts), prefix_width + utils::display_width(&entry.name) + entry.indicator.len())
+    };
+
+    let sections: Vec<(Option<&str>, Vec<Cell>)> = get_sections(format_list, opts)
+        .into_iter()
+        .map(|(title, entries)| (title, entries.iter().map(get_name).collect()))
+        .collect();
+    error::check_strict()?;
+
+    let line_width = line_width(opts);
+    if opts.size_blocks && opts.show_total && opts.dir_headers && list.dir.is_some() {
+        println!("total {}", files::format_blocks(list.blocks, opts));
+    }
+    for (i, (title, names)) in sections.into_iter().enumerate() {
+        print_section_title(title, i == 0);
+        if names.is_empty() {
+            continue;
+        }
+        match opts.layout {
+            Layout::OnePerLine => names.iter().for_each(|(name, _)| println!("{}", name)),
+            Layout::Columns => get_grid_lines(&names, line_width, false, opts.tab_size).iter().for_each(|line| println!("{}", line)),
+            Layout::Across => get_grid_lines(&names, line_width, true, opts.tab_size).iter().for_each(|line| println!("{}", line)),
+            Layout::Commas => get_comma_lines(&names, line_width).iter().for_each(|line| println!("{}", line))
+        }
+    }
+
+    Ok(())
+}
+
+// -w 0 never wraps
+fn line_width(opts: &Opts) -> usize {
+    match opts.width.unwrap_or_else(utils::terminal_width) {
+        0 => usize::MAX,
+        width => width
+    }
+}
+
+// Names separated by a comma and space, wrapping before a name that wouldn't fit
+fn get_comma_lines(cells: &[Cell], line_width: usize) -> Vec<String> {
+    let mut lines = vec![];
+    let mut line = String::new();
+    let mut position = 0;
+    for (i, (name, width)) in cells.iter().enumerate() {
+        if i > 0 {
+            line.push(',');
+            if position + width + 2 < line_width {
+                line.push(' ');
+                position += 2;
+            } else {
+                lines.push(std::mem::take(&mut line));
+                position = 0;
+            }
+        }
+        line += name;
+        position += width;
+    }
+    lines.push(line);
+    lines
+}
+
+// Like GNU ls, names go down the columns, or across the rows for -x, and the grid
+// takes as few rows as fit the line
+fn get_grid_lines(cells: &[Cell], line_width: usize, across: bool, tab_size: usize) -> Vec<String> {
+    let widths = fit_columns(cells.iter().map(|(_, width)| *width), cells.len(), line_width, across);
+    let columns = widths.len();
+    let rows = cells.len().div_ceil(columns);
+    let index = |row: usize, column: usize| if across { row * columns + column } else { column * rows + row };
+
+    let mut lines = vec![];
+    for row in 0..rows {
+        let mut line = String::new();
+        let mut position = 0;
+        for (column, column_width) in widths.iter().enumerate() {
+            let Some((name, width)) = cells.get(index(row, column)) else {
+                break;
+            };
+            line += name;
+            if column + 1 < columns && index(row, column + 1) < cells.len() {
+                indent(&mut line, position + width, position + column_width, tab_size);
+            }
+            position += column_width;
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+// Pads from one column of the line to another, using tabs wherever one doesn't overshoot
+fn indent(line: &mut String, mut from: usize, to: usize, tab_size: usize) {
+    while from < to {
+        if tab_size != 0 && to / tab_size > (from + 1) / tab_size {
+            line.push('\t');
+            from += tab_size - from % tab_size;
+        } else {
+            line.push(' ');
+            from += 1;
+        }
+    }
+}
+
+// Widths of the columns, separators included, for the most columns that fit. Every
+// candidate column count is tried at once, dropping the ones that overflow.
+fn fit_columns(widths: impl Iterator<Item = usize>, count: usize, line_width: usize, across: bool) -> Vec<usize> {
+    const MIN_COLUMN_WIDTH: usize = 3;
+
+    let max_columns = (line_width / MIN_COLUMN_WIDTH).clamp(1, count.max(1));
+    let mut layouts: Vec<(bool, usize, Vec<usize>)> = (1..=max_columns)
+        .map(|columns| (true, columns * MIN_COLUMN_WIDTH, vec![MIN_COLUMN_WIDTH; columns]))
+        .collect();
+
+    for (i, width) in widths.enumerate() {
+        for (columns, (valid, line_len, column_widths)) in (1..=max_columns).zip(layouts.iter_mut()) {
+            if !*valid {
+                continue;
+            }
+
+            let column = if across { i % columns } else { i / count.div_ceil(columns) };
+            let width = if column == columns - 1 { width } else { width + 2 };
+            if column_widths[column] < width {
+                *line_len += width - column_widths[column];
+                column_widths[column] = width;
+                *valid = *line_len < line_width;
+            }
+        }
+    }
+
+    layouts
+        .into_iter()
+        .rev()
+        .find(|(valid, _, _)| *valid)
+        .map_or_else(|| vec![0], |(_, _, column_widths)| column_widths)
+}
+
+// Like GNU ls, only the default, iso and two format custom styles switch format for times older
+// than six months (or in the future)
+fn format_time(time: SystemTime, now: SystemTime, style: &TimeStyle) -> String {
+    const SIX_MONTHS: Duration = Duration::from_secs(31556952 / 2);
+
+    let recent = time <= now && now.duration_since(time).is_ok_and(|age| age < SIX_MONTHS);
+    let format = match style {
+        TimeStyle::Default if recent => "%b %e %H:%M",
+        TimeStyle::Default => "%b %e  %Y",
+        TimeStyle::Iso if recent => "%m-%d %H:%M",
+        TimeStyle::Iso => "%Y-%m-%d ",
+        TimeStyle::LongIso => "%Y-%m-%d %H:%M",
+        TimeStyle::FullIso => "%Y-%m-%d %H:%M:%S.%f %z",
+        TimeStyle::Custom(_, format) if recent => format,
+        TimeStyle::Custom(format, _) => format,
+        TimeStyle::Relative => return format_relative_time(time, now)
+    };
+
+    DateTime::<Local>::from(time).format(format).to_string()
+}
+
+fn format_relative_time(time: SystemTime, now: SystemTime) -> String {
+    let (secs, future) = match now.duration_since(time) {
+        Ok(age) => (age.as_secs(), false),
+        Err(e) => (e.duration().as_secs(), true)
+    };
+
+    let (count, unit) = match secs {
+        0 => return String::from("now"),
+        1..=59 => (secs, "sec"),
+        60..=3599 => (secs / 60, "min"),
+        3600..=86399 => (secs / 3600, "hour"),
+        86400..=2591999 => (secs / 86400, "day"),
+        2592000..=31103999 => (secs / 2592000, "month"),
+        // Twelve 30 day months fall a few days short of a year, still call it one
+        _ => ((secs / 31556952).max(1), "year")
+    };
+    let plural = if count != 1 && !matches!(unit, "sec" | "min") { "s" } else { "" };
+
+    if future {
+        format!("in {} {}{}", count, unit, plural)
+    } else {
+        format!("{} {}{} ago", count, unit, plural)
     }
-    println!();
 }
 
 enum FormattedFile {
     File,
     Dir,
-    Sym
+    Sym,
+    // Devices, FIFOs and sockets
+    Special
 }
 
 impl Clone for FormattedFile {
@@ -86,73 +700,377 @@ impl Clone for FormattedFile {
         match self {
             FormattedFile::File => FormattedFile::File,
             FormattedFile::Dir => FormattedFile::Dir,
-            FormattedFile::Sym => FormattedFile::Sym
+            FormattedFile::Sym => FormattedFile::Sym,
+            FormattedFile::Special => FormattedFile::Special
+        }
+    }
+}
+
+impl Column {
+    // The long format columns in GNU ls order, unless picked with --fields
+    fn visible(opts: &Opts) -> Vec<Column> {
+        if let Some(fields) = &opts.fields {
+            return fields.clone();
+        }
+
+        let mut columns = vec![];
+        if opts.inode {
+            columns.push(Column::Inode);
+        }
+        if opts.size_blocks {
+            columns.push(Column::Blocks);
+        }
+        match opts.octal_permissions {
+            _ if !opts.show_permissions => (),
+            OctalPermissions::Off => columns.push(Column::Mode),
+            OctalPermissions::Both => columns.extend([Column::Octal, Column::Mode]),
+            OctalPermissions::Replace => columns.push(Column::Octal)
+        }
+        columns.push(Column::Links);
+        if opts.show_owner {
+            columns.push(Column::User);
+        }
+        if opts.show_group {
+            columns.push(Column::Group);
+        }
+        if opts.flags {
+            columns.push(Column::Flags);
+        }
+        if opts.author {
+            columns.push(Column::Author);
+        }
+        if opts.context {
+            columns.push(Column::Context);
         }
+        if opts.caps {
+            columns.push(Column::Caps);
+        }
+        if opts.show_size {
+            columns.push(Column::Size);
+        }
+        if opts.show_time {
+            columns.push(Column::Modified);
+        }
+        if opts.git {
+            columns.push(Column::Git);
+        }
+        if opts.mime {
+            columns.push(Column::Mime);
+        }
+        if opts.checksum.is_some() {
+            columns.push(Column::Checksum);
+        }
+        columns.push(Column::Name);
+        columns
+    }
+
+    // `visible` narrowed down for one listing, --smart-group drops the group
+    // when it matches the owner everywhere and --git outside a repository
+    fn for_entries(entries: &[FormattedEntry], opts: &Opts) -> Vec<Column> {
+        let mut columns = Self::visible(opts);
+        if opts.smart_group && entries.iter().all(|entry| entry.group == entry.user) {
+            columns.retain(|column| !matches!(column, Column::Group));
+        }
+        if entries.iter().all(|entry| entry.git.is_empty()) {
+            columns.retain(|column| !matches!(column, Column::Git));
+        }
+        columns
     }
 }
 
 struct FormattedEntry {
+    pub inode: String,
+    pub blocks: String,
+    pub octal: String,
     pub mode: String,
     pub links: String,
     pub user: String,
     pub group: String,
+    pub flags: String,
+    pub author: String,
+    pub context: String,
+    pub caps: String,
     pub size: String,
+    // Major and minor number for devices, which take the place of the size
+    pub device: Option<(String, String)>,
     pub modified: String,
+    pub git: String,
+    pub mime: String,
+    pub checksum: String,
+    // Quoted for display, see `file_name` for the unquoted name its color is picked by
     pub name: String,
-    pub sym: Option<((String, PathBuf), FormattedFile)>
+    pub file_name: String,
+    // The name as printed, with colors, a symlink target or hardlinks. Set by `pad`,
+    // which works out the padding from the plain `name`
+    pub colored_name: String,
+    // Padding after the colored name, for --fields with columns after it
+    pub name_pad: usize,
+    // The -F suffix, printed uncolored after the name
+    pub indicator: &'static str,
+    // A regular file with more than one link, colored `mh` when that is set
+    pub linked: bool,
+    // Other names of the same file in this listing with --hardlinks
+    pub hardlinks: String,
+    // Attribute names and sizes printed below the row with -@
+    pub xattrs: Vec<(String, usize)>,
+    // The link target as written, and where it leads when it resolves
+    pub sym: Option<(String, Option<(PathBuf, FormattedFile)>)>,
+    pub kind: FormattedFile
 }
 
 struct CountedEntry {
-    pub mode: usize,
-    pub links: usize,
-    pub user: usize,
-    pub group: usize,
-    pub size: usize,
-    pub modified: usize
+    pub widths: HashMap<Column, usize>,
+    // Device numbers are measured apart so each half lines up in the size column
+    pub major: usize,
+    pub minor: usize,
+    // Sizes with a unit like `4.0K` are split so the units line up in a column
+    pub number: usize,
+    pub unit: usize
 }
 
 impl FormattedEntry {
-    pub fn new(entry: &files::FilesEntry, name: &str) -> Self {
+    pub fn new(entry: &files::FilesEntry, name: &OsStr, opts: &Opts) -> Self {
+        // -n never looks the ids up, which can be slow with NSS backed by LDAP
+        let user = if opts.numeric_ids {
+            entry.uid.to_string()
+        } else {
+            entry.get_user_str().unwrap_or_else(|_| String::from("-"))
+        };
+        // Caps, mime types and xattrs take syscalls or reads, skip them unless shown
+        let columns = if opts.long_format { Column::visible(opts) } else { vec![] };
+        let shows_mime = opts.mime && columns.contains(&Column::Mime);
+
         Self {
-            mode: entry.get_mode_str(),
+            inode: entry.inode.to_string(),
+            blocks: files::format_blocks(entry.blocks, opts),
+            octal: format!("{:04o}", entry.get_permission_bits()),
+            // The marker costs a listxattr call, only long rows show it
+            mode: match if opts.long_format { entry.get_mode_marker() } else { None } {
+                Some(marker) => format!("{}{}", entry.get_mode_str(), marker),
+                None => entry.get_mode_str()
+            },
             links: format!("{}", entry.get_link_count()),
-user: match entry.get_user_str() {
-                Err(_) => String::from("-"),
-                Ok(p) => p
+            user: user.clone(),
+            group: if opts.numeric_ids {
+                entry.gid.to_string()
+            } else {
+                entry.get_group_str().unwrap_or_else(|_| String::from("-"))
+            },
+            flags: entry.get_flags_str(),
+            // The author is the owner on Linux, as with GNU ls
+            author: user,
+            // Like GNU ls, a missing context is `?` rather than an error
+            context: if opts.context {
+                entry.get_security_context().unwrap_or_else(|| String::from("?"))
+            } else {
+                String::new()
+            },
+            caps: if opts.caps && columns.contains(&Column::Caps) { entry.get_capabilities() } else { String::new() },
+            // --sparse adds the allocated size after the apparent one
+            size: if opts.sparse && entry.is_sparse() {
+                format!("{} ({})", files::format_size(entry.size, opts), files::format_size(entry.blocks * 512, opts))
+            } else {
+                files::format_size(entry.size, opts)
             },
-            group: match entry.get_group_str() {
-                Err(_) => String::from("-"),
-                Ok(p) => p
+            device: entry
+                .get_device_numbers()
+                .map(|(major, minor)| (major.to_string(), minor.to_string())),
+            modified: entry
+                .time(&opts.time)
+                .map_or_else(|| String::from("-"), |time| format_time(time, SystemTime::now(), &opts.time_style)),
+            git: entry.git.map(String::from_iter).unwrap_or_default(),
+            // Only regular files are sniffed, reading no more than their first block
+            mime: match entry.file_type {
+                FilesType::File(_) if shows_mime && !entry.on_disk => mime::from_extension(files::FilesEntry::extension_of(&name.to_string_lossy()))
+                    .unwrap_or("-")
+                    .to_string(),
+                FilesType::File(ref path) if shows_mime => mime::detect(path, files::FilesEntry::extension_of(&name.to_string_lossy()))
+                    .unwrap_or("-")
+                    .to_string(),
+                _ => String::from("-")
             },
-            size: format!("{}", entry.size),
-            modified: entry.modified.clone(),
-            name: name.to_string(),
+            // Filled in for the whole listing at once, see `add_checksums`
+            checksum: String::new(),
+            name: quote(name, opts),
+            file_name: Path::new(name).file_name().unwrap_or(name).to_string_lossy().into_owned(),
+            colored_name: String::new(),
+            name_pad: 0,
+            indicator: Self::get_indicator(entry, opts),
+            linked: matches!(entry.file_type, FilesType::File(_)) && entry.get_link_count() > 1,
+            hardlinks: String::new(),
+            xattrs: if opts.long_format && opts.xattr_detail { entry.get_xattr_sizes() } else { vec![] },
             sym: match &entry.file_type {
-                FilesType::Sym(s, p) => Self::get_relative_path(s, p),
+                // A member's target is somewhere in the archive, not to be looked up on disk
+                FilesType::Sym(_, p) if !entry.on_disk => Some((quote(p.as_os_str(), opts), None)),
+                FilesType::Sym(s, p) => Some(Self::get_symlink_target(s, p, opts)),
                 _ => None
+            },
+            kind: match &entry.file_type {
+                FilesType::Dir(_) => FormattedFile::Dir,
+                FilesType::Sym(_, _) => FormattedFile::Sym,
+                FilesType::File(_) => FormattedFile::File,
+                _ => FormattedFile::Special
             }
         }
     }
 
-    fn get_deep_type(path: &PathBuf) -> FormattedFile {
-        if path.is_symlink() {
-            match std::fs::read_link(path) {
-                Err(_) => {
-                    eprintln!("Failed to read deep symlink: {}", path.kstr());
-                    FormattedFile::Sym
+    // Column titles for --header, in place of the values
+    fn header(opts: &Opts) -> Self {
+        Self {
+            inode: String::from("Inode"),
+            blocks: String::from("Blocks"),
+            octal: String::from("Octal"),
+            mode: String::from("Permissions"),
+            links: String::from("Links"),
+            user: String::from("User"),
+            group: String::from("Group"),
+            flags: String::from("Flags"),
+            author: String::from("Author"),
+            context: String::from("Context"),
+            caps: String::from("Capabilities"),
+            size: String::from("Size"),
+            device: None,
+            modified: String::from(match opts.time {
+                TimeField::Modified => "Modified",
+                TimeField::Accessed => "Accessed",
+                TimeField::Changed => "Changed",
+                TimeField::Birth => "Created"
+            }),
+            git: String::from("Git"),
+            mime: String::from("Type"),
+            checksum: String::from("Checksum"),
+            name: String::from("Name"),
+            file_name: String::new(),
+            colored_name: String::new(),
+            name_pad: 0,
+            indicator: "",
+            linked: false,
+            hardlinks: String::new(),
+            xattrs: vec![],
+            sym: None,
+            kind: FormattedFile::File
+        }
+    }
+
+    fn column(&self, column: Column) -> &str {
+        match column {
+            Column::Inode => &self.inode,
+            Column::Blocks => &self.blocks,
+            Column::Octal => &self.octal,
+            Column::Mode => &self.mode,
+            Column::Links => &self.links,
+            Column::User => &self.user,
+            Column::Group => &self.group,
+            Column::Flags => &self.flags,
+            Column::Author => &self.author,
+            Column::Context => &self.context,
+            Column::Caps => &self.caps,
+            Column::Size => &self.size,
+            Column::Modified => &self.modified,
+            Column::Git => &self.git,
+            Column::Mime => &self.mime,
+            Column::Checksum => &self.checksum,
+            Column::Name => &self.name
+        }
+    }
+
+    fn get_indicator(entry: &files::FilesEntry, opts: &Opts) -> &'static str {
+        match opts.indicator_style {
+            IndicatorStyle::None => return "",
+            IndicatorStyle::Slash => return if let FilesType::Dir(_) = entry.file_type { "/" } else { "" },
+            IndicatorStyle::FileType | IndicatorStyle::Classify => ()
+        }
+
+        // file-type is classify without the `*` for executables
+        match entry.file_type {
+            FilesType::Dir(_) => "/",
+            FilesType::Sym(_, _) => "@",
+            FilesType::Fifo(_) => "|",
+            FilesType::Socket(_) => "=",
+            FilesType::File(_) if matches!(opts.indicator_style, IndicatorStyle::Classify) && entry.get_permission_bits() & 0o111 != 0 => "*",
+            _ => ""
+        }
+    }
+
+    // Symlinks shown with their target don't get an indicator
+    fn shows_target(&self, opts: &Opts) -> bool {
+        self.sym.is_some() && (opts.long_format || opts.tree)
+    }
+
+    // Printed width of the name in long format, including a symlink target or indicator
+    fn name_width(&self) -> usize {
+        let width = match &self.sym {
+            Some((target, _)) => utils::display_width(&self.name) + 4 + utils::display_width(target),
+            None => utils::display_width(&self.name) + self.indicator.len()
+        };
+        if self.hardlinks.is_empty() {
+            width
+        } else {
+            width + 4 + utils::display_width(&self.hardlinks)
+        }
+    }
+
+    // Expects an entry already padded by `pad`
+    pub fn get_long_line(&self, columns: &[Column]) -> String {
+        columns
+            .iter()
+            .enumerate()
+            .map(|(i, column)| match column {
+                Column::Name if i + 1 < columns.len() => format!("{}{}", self.colored_name, " ".repeat(self.name_pad)),
+                Column::Name => self.colored_name.clone(),
+                column => self.column(*column).to_string()
+            })
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+
+    // Follows the chain one link at a time, each relative to the directory of the
+    // link before it. Chains that loop or run too long end as a symlink and
+    // missing targets give None.
+    fn get_deep_type(link: &Path, target: &Path) -> Option<(PathBuf, FormattedFile)> {
+        // The limit Linux uses before giving up with ELOOP
+        const MAX_HOPS: usize = 40;
+
+        let mut visited: HashSet<PathBuf> = HashSet::new();
+        let mut path = link.parent()?.join(target);
+        for _ in 0..MAX_HOPS {
+            let meta = path.symlink_metadata().ok()?;
+            if !meta.file_type().is_symlink() {
+                let file_type = if path.is_dir() { FormattedFile::Dir } else { FormattedFile::File };
+                return Some((path, file_type));
+            }
+            if !visited.insert(path.clone()) {
+                break;
+            }
+
+            match std::fs::read_link(&path) {
+                Err(e) => {
+                    error::report(&format!("failed to read deep symlink \"{}\"", path.kstr()), &e);
+                    break;
                 },
-                Ok(path) => Self::get_deep_type(&path)
+                Ok(next) => path = path.parent()?.join(next)
             }
-        } else if path.is_file() {
-            FormattedFile::File
-        } else {
-            FormattedFile::Dir
         }
+
+        Some((path, FormattedFile::Sym))
     }
 
-    fn get_relative_path(a: &PathBuf, b: &PathBuf) -> Option<((String, PathBuf), FormattedFile)> {
-        let rel = b.canonicalize_relative_to(&a.parent()?.to_path_buf()).ok()?;
-        return Some(((b.kstr(), rel.to_owned()), Self::get_deep_type(&rel)));
+    fn is_executable(meta: &std::fs::Metadata) -> bool {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            (meta.mode() & 0o111) != 0
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = meta;
+            false
+        }
+    }
+
+    // The target text is always known, resolving it is best effort
+    fn get_symlink_target(link: &Path, target: &Path, opts: &Opts) -> (String, Option<(PathBuf, FormattedFile)>) {
+        (quote(target.as_os_str(), opts), Self::get_deep_type(link, target))
     }
 
     pub fn pad(list: Vec<FormattedEntry>, opts: &Opts) -> Vec<FormattedEntry> {
@@ -166,31 +1084,57 @@ user: match entry.get_user_str() {
             .collect()
     }
 
+    // The name with the parts a --glob or --iglob filter matched shown inverted, only
+    // when colors are on and the quoted name still contains the name as is
+    fn highlighted_name(&self, opts: &Opts) -> String {
+        if (opts.glob.is_empty() && opts.iglob.is_empty()) || !colored::control::SHOULD_COLORIZE.should_colorize() {
+            return self.name.clone();
+        }
+        let (Some(offset), Some(spans)) = (self.name.find(&self.file_name), files::glob_spans(opts, &self.file_name)) else {
+            return self.name.clone();
+        };
+
+        let mut result = String::new();
+        let mut last = 0;
+        for span in spans {
+            let (start, end) = (offset + span.start, offset + span.end);
+            result += &self.name[last..start];
+            result += "\x1b[7m";
+            result += &self.name[start..end];
+            result += "\x1b[27m";
+            last = end;
+        }
+        result + &self.name[last..]
+    }
+
     pub fn get_colored_name(&self, opts: &Opts) -> String {
-        let result = if let Some(((target, target_path), file_type)) = &self.sym {
-            let name = self.name.bright_cyan().bold();
-            if opts.long_format {
-                let target = match file_type {
-                    FormattedFile::File => {
+        let name_text = self.highlighted_name(opts);
+        let result = if let Some((target, resolved)) = &self.sym {
+            let name = compute_type_color(&name_text, "ln").unwrap_or_else(|| name_text.bright_cyan().bold());
+            if opts.long_format || opts.tree {
+                let target = match resolved {
+                    None => compute_type_color(target, "or").unwrap_or_else(|| target.red().bold()),
+                    Some((target_path, FormattedFile::File)) => {
                         let meta = if target_path.is_symlink() { target_path.symlink_metadata() } else { target_path.metadata() };
                         match meta {
                             Ok(meta) => {
-                                if (meta.mode() & 0o111) != 0 {
-                                    target.green().bold()
+                                if Self::is_executable(&meta) {
+                                    compute_type_color(target, "ex").unwrap_or_else(|| target.green().bold())
                                 } else {
                                     //target.red().bold()
                                     ColoredString::from(target.to_string())
                                 }
                             },
                             Err(e) => {
-                                eprintln!("Failed to fetch target meta for: {}\n  Error: {}", target, e);
+                                error::report(&format!("failed to fetch target meta for \"{}\"", target), &e);
                                 //target.magenta().bold()
                                 target.green().bold()
                             }
                         }
                     },
-                    FormattedFile::Dir => target.blue().bold(),
-                    FormattedFile::Sym => target.bright_cyan().bold()
+                    Some((_, FormattedFile::Dir)) => compute_type_color(target, "di").unwrap_or_else(|| target.blue().bold()),
+                    Some((_, FormattedFile::Sym)) => compute_type_color(target, "ln").unwrap_or_else(|| target.bright_cyan().bold()),
+                    Some((_, FormattedFile::Special)) => ColoredString::from(target.to_string())
                 };
 
                 ColoredString::from(format!("{} -> {}",
@@ -199,55 +1143,471 @@ user: match entry.get_user_str() {
             } else {
                 name
             }
-        } else if self.mode.chars().nth(0).unwrap() == 'd' {
-            self.name.blue().bold()
+        } else if self.mode.starts_with('d') {
+            compute_type_color(&name_text, "di").unwrap_or_else(|| name_text.blue().bold())
+        } else if self.mode.starts_with('b') || self.mode.starts_with('c') {
+            let key = if self.mode.starts_with('b') { "bd" } else { "cd" };
+            compute_type_color(&name_text, key).unwrap_or_else(|| name_text.yellow().bold().on_black())
+        } else if self.mode.starts_with('p') {
+            compute_type_color(&name_text, "pi").unwrap_or_else(|| name_text.yellow().on_black())
+        } else if self.mode.starts_with('s') {
+            compute_type_color(&name_text, "so").unwrap_or_else(|| name_text.magenta().bold())
         } else if self.mode.contains("x") {
-            self.name.green().bold()
+            compute_type_color(&name_text, "ex").unwrap_or_else(|| name_text.green().bold())
+        } else if self.linked {
+            compute_type_color(&name_text, "mh").unwrap_or_else(|| ColoredString::from(name_text.clone()))
         } else {
-            ColoredString::from(self.name.clone())
+            ColoredString::from(name_text.clone())
         };
 
-        compute_color_for(result, &self.name.substr_after(self.name.rfind('.').map_or(0, |i|i+1))).to_string()
+        let indicator = if self.shows_target(opts) { "" } else { self.indicator };
+        let mut name = compute_color_for(result, &self.file_name).to_string() + indicator;
+        if opts.long_format && !self.hardlinks.is_empty() {
+            name += &format!(" => {}", self.hardlinks);
+        }
+        name
     }
 }
 
 impl CountedEntry {
     pub fn new() -> Self {
         Self {
-            mode: 0,
-            links: 0,
-            user: 0,
-            group: 0,
-            size: 0,
-            modified: 0
+            widths: HashMap::new(),
+            major: 0,
+            minor: 0,
+            number: 0,
+            unit: 0
         }
     }
 
+    // The digits and the unit after them, None for sizes that don't end in a number
+    // and unit, like a header or a --sparse allocation
+    fn split_unit(size: &str) -> Option<(&str, &str)> {
+        let number = size.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+        number.ends_with(|c: char| c.is_ascii_digit()).then(|| size.split_at(number.len()))
+    }
+
     fn cmp_set(c: &mut usize, n: usize) {
         if n > *c {
             *c = n;
         }
     }
 
+    fn width(&self, column: Column) -> usize {
+        self.widths.get(&column).copied().unwrap_or(0)
+    }
+
     pub fn next(&mut self, entry: &FormattedEntry) {
-        Self::cmp_set(&mut self.mode, entry.mode.len());
-        Self::cmp_set(&mut self.links, entry.links.len());
-        Self::cmp_set(&mut self.user, entry.user.len());
-        Self::cmp_set(&mut self.group, entry.group.len());
-        Self::cmp_set(&mut self.size, entry.size.len());
-        Self::cmp_set(&mut self.modified, entry.modified.len());
+        for (_, column) in Column::NAMES {
+            let len = match (column, &entry.device) {
+                (Column::Size, Some((major, minor))) => {
+                    Self::cmp_set(&mut self.major, major.len());
+                    Self::cmp_set(&mut self.minor, minor.len());
+                    self.major + 2 + self.minor
+                },
+                (Column::Size, None) => match Self::split_unit(&entry.size) {
+                    Some((number, unit)) => {
+                        Self::cmp_set(&mut self.number, number.len());
+                        Self::cmp_set(&mut self.unit, unit.len());
+                        self.number + self.unit
+                    },
+                    None => utils::display_width(&entry.size)
+                },
+                (Column::Name, _) => entry.name_width(),
+                (column, _) => utils::display_width(entry.column(column))
+            };
+            Self::cmp_set(self.widths.entry(column).or_insert(0), len);
+        }
+    }
+
+    // Numbers are right aligned, names left aligned unless they're numeric ids like GNU ls
+    fn align(&self, column: Column, text: &str, opts: &Opts) -> String {
+        let width = self.width(column);
+        let right = match column {
+            Column::Inode | Column::Blocks | Column::Octal | Column::Links | Column::Size | Column::Modified => true,
+            Column::User | Column::Group | Column::Author => opts.numeric_ids,
+            // Permissions are left aligned so a missing ACL marker pads after them
+            _ => false
+        };
+
+        let pad = " ".repeat(width.saturating_sub(utils::display_width(text)));
+        if right {
+            pad + text
+        } else {
+            String::from(text) + &pad
+        }
+    }
+
+    // Staged changes in green, unstaged and untracked ones in red
+    fn color_git(status: &str) -> String {
+        const CHANGES: &str = "MADRCTU";
+
+        status
+            .chars()
+            .enumerate()
+            .map(|(i, c)| match i {
+                0 if CHANGES.contains(c) => c.to_string().green().to_string(),
+                1 if CHANGES.contains(c) || c == '?' => c.to_string().red().to_string(),
+                _ => c.to_string()
+            })
+            .collect()
     }
 
     pub fn apply(&self, e: &FormattedEntry, opts: &Opts) -> FormattedEntry {
         FormattedEntry {
-            mode: format!("{:>width$}", e.mode, width = self.mode),
-            links: format!("{:>width$}", e.links, width = self.links),
-            user: format!("{:width$}", e.user, width = self.user),
-            group: format!("{:width$}", e.group, width = self.group),
-            size: format!("{:>width$}", e.size, width = self.size),
-            modified: format!("{:>width$}", e.modified, width = self.modified),
-            name: e.get_colored_name(opts),
-            sym: e.sym.clone()
+            inode: self.align(Column::Inode, &e.inode, opts),
+            blocks: self.align(Column::Blocks, &e.blocks, opts),
+            octal: self.align(Column::Octal, &e.octal, opts),
+            mode: self.align(Column::Mode, &e.mode, opts),
+            links: self.align(Column::Links, &e.links, opts),
+            user: self.align(Column::User, &e.user, opts),
+            group: self.align(Column::Group, &e.group, opts),
+            flags: self.align(Column::Flags, &e.flags, opts),
+            author: self.align(Column::Author, &e.author, opts),
+            context: self.align(Column::Context, &e.context, opts),
+            caps: self.align(Column::Caps, &e.caps, opts),
+            size: match &e.device {
+                // Minor numbers line up, the major takes what's left of the size width
+                Some((major, minor)) => format!("{:>major_width$}, {:>minor_width$}", major, minor,
+                    major_width = self.width(Column::Size) - self.minor - 2,
+                    minor_width = self.minor),
+                None => match Self::split_unit(&e.size) {
+                    Some((number, unit)) => self.align(Column::Size, &format!("{:>number_width$}{:unit_width$}", number, unit,
+                        number_width = self.number,
+                        unit_width = self.unit), opts),
+                    None => self.align(Column::Size, &e.size, opts)
+                }
+            },
+            device: e.device.clone(),
+            modified: self.align(Column::Modified, &e.modified, opts),
+            git: Self::color_git(&self.align(Column::Git, &e.git, opts)),
+            mime: self.align(Column::Mime, &e.mime, opts),
+            checksum: self.align(Column::Checksum, &e.checksum, opts),
+            name: e.name.clone(),
+            colored_name: e.get_colored_name(opts),
+            name_pad: self.width(Column::Name) - e.name_width(),
+            file_name: e.file_name.clone(),
+            indicator: e.indicator,
+            linked: e.linked,
+            hardlinks: e.hardlinks.clone(),
+            xattrs: e.xattrs.clone(),
+            sym: e.sym.clone(),
+            kind: e.kind.clone()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::params::Params;
+    use crate::testdir::TestDir;
+
+    fn params(args: &[&str]) -> Params {
+        Params::from_args(args.iter().map(|arg| arg.to_string())).unwrap()
+    }
+
+    // Regular files with their size and owner, all modified early in 1970 so the
+    // listing doesn't depend on the current time
+    fn fixture(entries: &[(&str, u64, (u32, u32))]) -> files::FilesList {
+        let entries = entries.iter().map(|(name, size, ids)| files::FilesEntry::new_virtual(
+            FilesType::File(PathBuf::from("/fixture").join(name)),
+            0o644,
+            *size,
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000),
+            *ids));
+
+        files::FilesList {
+            entries: entries.collect(),
+            dir: None,
+            up_dir: None,
+            blocks: 0,
+            hidden: 0,
+            children: vec![]
         }
     }
+
+    // The rows `output_one_list` prints, without colors
+    fn long_lines(list: &files::FilesList, args: &[&str]) -> Vec<String> {
+        colored::control::set_override(false);
+        let opts = params(args).opts;
+        let entries = get_formatted_list(list, &opts);
+        let columns = Column::for_entries(&entries, &opts);
+        FormattedEntry::pad(entries, &opts).iter().map(|entry| entry.get_long_line(&columns)).collect()
+    }
+
+    #[test]
+    fn owner_and_group_columns() {
+        let list = fixture(&[("a.txt", 5, (0, 0)), ("big.bin", 12345, (1000, 100))]);
+        let lines = |flags: &str| long_lines(&list, &["-n", flags, "--time-style=+%Y"]);
+
+        assert_eq!(lines("-l"), [
+            "-rw-r--r-- 1    0   0     5 1970 a.txt",
+            "-rw-r--r-- 1 1000 100 12345 1970 big.bin"
+        ]);
+        assert_eq!(lines("-lo"), [
+            "-rw-r--r-- 1    0     5 1970 a.txt",
+            "-rw-r--r-- 1 1000 12345 1970 big.bin"
+        ]);
+        assert_eq!(lines("-lg"), [
+            "-rw-r--r-- 1   0     5 1970 a.txt",
+            "-rw-r--r-- 1 100 12345 1970 big.bin"
+        ]);
+        assert_eq!(lines("-log"), [
+            "-rw-r--r-- 1     5 1970 a.txt",
+            "-rw-r--r-- 1 12345 1970 big.bin"
+        ]);
+        assert_eq!(lines("-go"), lines("-log"));
+    }
+
+    #[test]
+    fn smart_group_hides_only_a_redundant_group() {
+        let args = ["-ln", "--smart-group", "--time-style=+%Y"];
+        let same = fixture(&[("a", 1, (1000, 1000)), ("b", 2, (0, 0))]);
+        assert_eq!(long_lines(&same, &args), [
+            "-rw-r--r-- 1 1000 1 1970 a",
+            "-rw-r--r-- 1    0 2 1970 b"
+        ]);
+
+        // One file in another group, like wheel, brings the column back for all
+        let mixed = fixture(&[("a", 1, (1000, 1000)), ("b", 2, (0, 10))]);
+        assert_eq!(long_lines(&mixed, &args), [
+            "-rw-r--r-- 1 1000 1000 1 1970 a",
+            "-rw-r--r-- 1    0   10 2 1970 b"
+        ]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn indicator_styles() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TestDir::new();
+        dir.dir("sub");
+        dir.file("plain", b"");
+        let run = dir.file("run", b"#!/bin/sh\n");
+        std::fs::set_permissions(&run, std::fs::Permissions::from_mode(0o755)).unwrap();
+        dir.symlink("plain", "link");
+
+        let list = files::FilesList::new(dir.path(), &params(&[]).opts).unwrap();
+        let indicators = |args: &[&str]| -> Vec<(String, &str)> {
+            let opts = params(args).opts;
+            get_formatted_list(&list, &opts).into_iter().map(|entry| (entry.name, entry.indicator)).collect()
+        };
+        let expected = |suffixes: [&'static str; 4]| -> Vec<(String, &str)> {
+            ["link", "plain", "run", "sub"].into_iter().map(String::from).zip(suffixes).collect()
+        };
+
+        assert_eq!(indicators(&["--indicator-style=none"]), expected(["", "", "", ""]));
+        assert_eq!(indicators(&["--indicator-style=slash"]), expected(["", "", "", "/"]));
+        assert_eq!(indicators(&["--indicator-style=file-type"]), expected(["@", "", "", "/"]));
+        assert_eq!(indicators(&["--indicator-style=classify"]), expected(["@", "", "*", "/"]));
+        assert_eq!(indicators(&["-F"]), indicators(&["--indicator-style=classify"]));
+        assert_eq!(indicators(&["-p"]), indicators(&["--indicator-style=slash"]));
+        assert_eq!(indicators(&["--file-type"]), indicators(&["--indicator-style=file-type"]));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn escape_keeps_a_newline_on_one_line() {
+        let dir = TestDir::new();
+        dir.file("a\nb", b"");
+        dir.symlink("a\nb", "link");
+
+        let list = files::FilesList::new(dir.path(), &params(&[]).opts).unwrap();
+        let lines = long_lines(&list, &["-lb"]);
+        assert!(lines.iter().all(|line| !line.contains('\n')));
+        assert!(lines[0].ends_with(" a\\nb"), "{:?}", lines[0]);
+        assert!(lines[1].ends_with(" link -> a\\nb"), "{:?}", lines[1]);
+    }
+
+    // macOS refuses names that aren't UTF-8, Linux stores any bytes
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn names_that_are_not_utf8_are_listed() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = TestDir::new();
+        std::fs::write(dir.path().join(OsStr::from_bytes(b"caf\xe9")), b"").unwrap();
+        dir.file("plain", b"");
+
+        let list = files::FilesList::new(dir.path(), &params(&[]).opts).unwrap();
+        assert_eq!(list.entries.len(), 2);
+
+        let short = |args: &[&str]| -> Vec<String> {
+            get_formatted_list(&list, &params(args).opts).into_iter().map(|entry| entry.name).collect()
+        };
+        assert_eq!(short(&["--literal"]), ["caf\u{FFFD}", "plain"]);
+        assert_eq!(short(&["-b"]), ["caf\\351", "plain"]);
+        assert_eq!(short(&["--literal", "-q"]), ["caf?", "plain"]);
+
+        let long = long_lines(&list, &["-l", "--quoting-style=shell-escape"]);
+        assert!(long[0].ends_with(" 'caf'$'\\351'"), "{:?}", long[0]);
+    }
+
+    #[test]
+    fn sparse_files_show_their_allocation() {
+        use std::io::{Seek, SeekFrom, Write};
+
+        let dir = TestDir::new();
+        dir.file("dense", &[1; 8192]);
+        let mut sparse = std::fs::File::create(dir.path().join("sparse")).unwrap();
+        sparse.seek(SeekFrom::Start(1 << 20)).unwrap();
+        sparse.write_all(b"end").unwrap();
+        sparse.set_len((1 << 20) + 3).unwrap();
+        drop(sparse);
+
+        let list = files::FilesList::new(dir.path(), &params(&[]).opts).unwrap();
+        let lines = long_lines(&list, &["-l", "--sparse"]);
+        assert!(!lines[0].contains('('), "{:?}", lines[0]);
+        let allocated = list.entries[1].blocks * 512;
+        assert!(lines[1].contains(&format!(" 1048579 ({}) ", allocated)), "{:?}", lines[1]);
+
+        // Without --sparse only the apparent size is shown
+        assert!(long_lines(&list, &["-l"]).iter().all(|line| !line.contains('(')));
+    }
+
+    #[test]
+    fn mime_types_only_for_long_rows() {
+        let list = fixture(&[("notes.txt", 0, (0, 0))]);
+        let mime = |args: &[&str]| get_formatted_list(&list, &params(args).opts)[0].mime.clone();
+        assert_eq!(mime(&["-l", "--mime"]), "text/plain");
+        // Short format never shows the column, so it isn't worked out
+        assert_eq!(mime(&["--mime"]), "-");
+        assert_eq!(mime(&["-l"]), "-");
+    }
+
+    #[test]
+    fn dir_headings_follow_operands_and_recursion() {
+        let dir = TestDir::new();
+        dir.dir("sub");
+        let heading = |args: &[&str], operands: usize| {
+            let opts = params(args).opts;
+            let list = files::FilesList::new(dir.path(), &opts).unwrap();
+            let mut lists = vec![];
+            flatten(&list, &mut lists);
+            lists.iter().map(|list| dir_heading(list, operands, &opts)).collect::<Vec<_>>()
+        };
+        let root = format!("{}:", dir.path().display());
+        let sub = format!("{}:", dir.path().join("sub").display());
+
+        // One per line is the layout when piped, it still gets headings
+        assert_eq!(heading(&["-1", "-R"], 1), vec![Some(root.clone()), Some(sub)]);
+        assert_eq!(heading(&["-1"], 2), vec![Some(root)]);
+        assert_eq!(heading(&["-1"], 1), vec![None]);
+        assert_eq!(heading(&["-1", "-R", "--no-dir-headers"], 1), vec![None, None]);
+    }
+
+    fn cells(names: &[&str]) -> Vec<Cell> {
+        names.iter().map(|name| (name.to_string(), utils::display_width(name))).collect()
+    }
+
+    #[test]
+    fn commas_wrap_before_the_line_width() {
+        let names = cells(&["aa", "bb", "cc"]);
+        // `aa, bb, cc` takes 10 columns and has to stay below the width
+        assert_eq!(get_comma_lines(&names, 11), ["aa, bb, cc"]);
+        assert_eq!(get_comma_lines(&names, 10), ["aa, bb,", "cc"]);
+        assert_eq!(get_comma_lines(&names, 7), ["aa, bb,", "cc"]);
+        assert_eq!(get_comma_lines(&names, 6), ["aa,", "bb,", "cc"]);
+        assert_eq!(get_comma_lines(&names, 1), ["aa,", "bb,", "cc"]);
+        assert_eq!(get_comma_lines(&names, usize::MAX), ["aa, bb, cc"]);
+        assert_eq!(get_comma_lines(&cells(&["only"]), 2), ["only"]);
+    }
+
+    #[test]
+    fn commas_measure_names_without_colors() {
+        let colored = cells(&["\x1b[1;34maa\x1b[0m", "bb", "\x1b[32mcc\x1b[0m"]);
+        assert_eq!(get_comma_lines(&colored, 11), ["\x1b[1;34maa\x1b[0m, bb, \x1b[32mcc\x1b[0m"]);
+        assert_eq!(get_comma_lines(&colored, 10).len(), 2);
+    }
+
+    #[test]
+    fn width_reflows_the_grid() {
+        let names: Vec<String> = (1..=12).map(|i| format!("name{:02}.rs", i)).collect();
+        let names = cells(&names.iter().map(String::as_str).collect::<Vec<_>>());
+        let grid = |args: &[&str]| get_grid_lines(&names, line_width(&params(args).opts), false, 0);
+
+        // Three columns of 9 wide names and 2 spaces take 31, a fourth would need 42
+        let narrow = grid(&["-w", "40"]);
+        assert_eq!(narrow.len(), 4);
+        assert_eq!(narrow[0], "name01.rs  name05.rs  name09.rs");
+        let wide = grid(&["--width=80"]);
+        assert_eq!(wide.len(), 2);
+        assert_eq!(wide[1], "name02.rs  name04.rs  name06.rs  name08.rs  name10.rs  name12.rs");
+        assert_eq!(grid(&["-w", "0"]).len(), 1);
+        assert_eq!(grid(&["-w", "5"]).len(), 12);
+    }
+
+    #[test]
+    fn width_moves_where_commas_wrap() {
+        let names = cells(&["aa", "bb", "cc"]);
+        assert_eq!(get_comma_lines(&names, line_width(&params(&["-w", "10"]).opts)), ["aa, bb,", "cc"]);
+        assert_eq!(get_comma_lines(&names, line_width(&params(&["-w", "0"]).opts)), ["aa, bb, cc"]);
+        assert!(Params::from_args(["-w".to_string(), "wide".to_string()]).is_err());
+    }
+
+    #[test]
+    fn full_time_shows_nanoseconds() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        for (nanos, fraction) in [(123_456_789, ".123456789"), (7, ".000000007"), (0, ".000000000")] {
+            let time = now - Duration::from_secs(60) + Duration::from_nanos(nanos);
+            let full = format_time(time, now, &TimeStyle::FullIso);
+            let parts: Vec<&str> = full.split(' ').collect();
+            assert_eq!(parts.len(), 3, "{:?}", full);
+            assert!(parts[1].ends_with(fraction), "{:?} should end in {}", full, fraction);
+            assert!(parts[2].starts_with(['+', '-']) && parts[2].len() == 5, "{:?} should end in an offset", full);
+        }
+    }
+
+    #[test]
+    fn full_time_reads_nanoseconds_from_disk() {
+        let dir = TestDir::new();
+        let mtime = SystemTime::UNIX_EPOCH + Duration::new(1_000_000_000, 123_456_789);
+        std::fs::File::create(dir.file("file", b"")).unwrap().set_modified(mtime).unwrap();
+
+        let list = files::FilesList::new(dir.path(), &params(&[]).opts).unwrap();
+        let lines = long_lines(&list, &["--full-time"]);
+        let expected = DateTime::<Local>::from(mtime).format("%Y-%m-%d %H:%M:%S.123456789 %z").to_string();
+        assert!(lines[0].contains(&expected), "{:?} should contain {:?}", lines[0], expected);
+    }
+
+    fn relative(age: i64) -> String {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        let time = if age >= 0 { now - Duration::from_secs(age as u64) } else { now + Duration::from_secs(age.unsigned_abs()) };
+        format_relative_time(time, now)
+    }
+
+    #[test]
+    fn relative_times_in_the_past() {
+        assert_eq!(relative(0), "now");
+        assert_eq!(relative(1), "1 sec ago");
+        assert_eq!(relative(59), "59 sec ago");
+        assert_eq!(relative(60), "1 min ago");
+        assert_eq!(relative(3599), "59 min ago");
+        assert_eq!(relative(3600), "1 hour ago");
+        assert_eq!(relative(7200), "2 hours ago");
+        assert_eq!(relative(86400), "1 day ago");
+        assert_eq!(relative(86400 * 29), "29 days ago");
+        assert_eq!(relative(86400 * 30), "1 month ago");
+        assert_eq!(relative(86400 * 200), "6 months ago");
+        assert_eq!(relative(31556952), "1 year ago");
+        assert_eq!(relative(31556952 * 3), "3 years ago");
+    }
+
+    #[test]
+    fn relative_times_turn_into_years_after_eleven_months() {
+        assert_eq!(relative(2592000 * 12 - 1), "11 months ago");
+        assert_eq!(relative(2592000 * 12), "1 year ago");
+        assert_eq!(relative(31556952 - 1), "1 year ago");
+        assert_eq!(relative(31556952 * 2 - 1), "1 year ago");
+        assert_eq!(relative(-2592000 * 12), "in 1 year");
+    }
+
+    #[test]
+    fn relative_times_in_the_future() {
+        assert_eq!(relative(-1), "in 1 sec");
+        assert_eq!(relative(-90), "in 1 min");
+        assert_eq!(relative(-86400 * 2), "in 2 days");
+    }
 }
@@ -0,0 +1,159 @@
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use crate::utils::glob_match;
+
+struct Rule {
+    // Directory of the file the rule came from, relative to the repository root
+    base: PathBuf,
+    segments: Vec<String>,
+    anchored: bool,
+    negate: bool,
+    dir_only: bool
+}
+
+pub struct GitIgnore {
+    root: PathBuf,
+    rules: Vec<Rule>
+}
+
+impl GitIgnore {
+    // Loads `.git/info/exclude` and every `.gitignore` from the repository root
+    // down to `dir`. Returns None when `dir` is not inside a git work tree.
+    pub fn for_dir(dir: &Path) -> Option<Self> {
+        let root = dir.ancestors().find(|d| d.join(".git").exists())?.to_path_buf();
+        let mut result = Self { root, rules: vec![] };
+
+        result.read_rules(&result.root.join(".git").join("info").join("exclude"), Path::new(""));
+
+        let rel = dir.strip_prefix(&result.root).ok()?.to_path_buf();
+        let mut base = PathBuf::new();
+        result.read_rules(&result.root.join(".gitignore"), &base);
+        for component in rel.components() {
+            base.push(component);
+            result.read_rules(&result.root.join(&base).join(".gitignore"), &base);
+        }
+
+        Some(result)
+    }
+
+    fn read_rules(&mut self, file: &Path, base: &Path) {
+        let Ok(content) = fs::read_to_string(file) else {
+            return;
+        };
+
+        for line in content.lines() {
+            if let Some(rule) = Self::parse_rule(line, base) {
+                self.rules.push(rule);
+            }
+        }
+    }
+
+    fn parse_rule(line: &str, base: &Path) -> Option<Rule> {
+        // Trailing spaces are dropped unless escaped with a backslash
+        let mut line = line.trim_end_matches('\r');
+        while line.ends_with(' ') && !line.ends_with("\\ ") {
+            line = &line[..line.len() - 1];
+        }
+
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+
+        let (negate, line) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line)
+        };
+        let (dir_only, line) = match line.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, line)
+        };
+
+        // A slash anywhere but the end ties the pattern to the directory of its file
+        let anchored = line.contains('/');
+        let line = line.strip_prefix('/').unwrap_or(line);
+        if line.is_empty() {
+            return None;
+        }
+
+        Some(Rule {
+            base: base.to_path_buf(),
+            segments: line.split('/').map(String::from).collect(),
+            anchored,
+            negate,
+            dir_only
+        })
+    }
+
+    // Git can't re-include anything below an excluded directory, so each parent is checked first
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        let Ok(rel) = path.strip_prefix(&self.root) else {
+            return false;
+        };
+
+        let mut parent = PathBuf::new();
+        let mut components = rel.components().peekable();
+        while let Some(component) = components.next() {
+            parent.push(component);
+            let last = components.peek().is_none();
+            if self.matches(&parent, if last { is_dir } else { true }) {
+                return true;
+            }
+        }
+
+        false
+    }
+
+    // The last matching rule wins
+    fn matches(&self, rel: &Path, is_dir: bool) -> bool {
+        let mut ignored = false;
+        for rule in &self.rules {
+            if rule.dir_only && !is_dir {
+                continue;
+            }
+
+            let Ok(rel) = rel.strip_prefix(&rule.base) else {
+                continue;
+            };
+            let Some(segments) = rel
+                .components()
+                .map(|c| match c {
+                    Component::Normal(s) => s.to_str(),
+                    _ => None
+                })
+                .collect::<Option<Vec<&str>>>() else {
+                continue;
+            };
+
+            let matched = if rule.anchored {
+                match_segments(&rule.segments, &segments)
+            } else {
+                segments.last().is_some_and(|name| glob_match(&rule.segments[0], name))
+            };
+
+            if matched {
+                ignored = !rule.negate;
+            }
+        }
+
+        ignored
+    }
+}
+
+// `**` spans any number of directories, a trailing `/**` only what's inside
+fn match_segments(pattern: &[String], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((first, rest)) if first == "**" => {
+            if rest.is_empty() {
+                !path.is_empty()
+            } else {
+                (0..=path.len()).any(|i| match_segments(rest, &path[i..]))
+            }
+        },
+        Some((first, rest)) => match path.split_first() {
+            Some((name, path)) => glob_match(first, name) && match_segments(rest, path),
+            None => false
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex, OnceLock};
+
+pub struct GitStatus {
+    root: PathBuf,
+    // Index and worktree status by path relative to the repository root.
+    // Untracked or ignored directories are listed once with a trailing slash.
+    statuses: BTreeMap<String, [char; 2]>
+}
+
+// Statuses by repository root, so -R runs git once per repository
+fn get_cache() -> &'static Mutex<HashMap<PathBuf, Option<Arc<GitStatus>>>> {
+    static CACHE: OnceLock<Mutex<HashMap<PathBuf, Option<Arc<GitStatus>>>>> = OnceLock::new();
+    CACHE.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+impl GitStatus {
+    // The status of the repository holding `dir`, asked of `git status` the
+    // first time. Returns None outside a work tree or when git isn't available.
+    pub fn for_dir(dir: &Path) -> Option<Arc<Self>> {
+        let root = dir.ancestors().find(|d| d.join(".git").exists())?.to_path_buf();
+        get_cache()
+            .lock()
+            .unwrap()
+            .entry(root.clone())
+            .or_insert_with(|| Self::read(root).map(Arc::new))
+            .clone()
+    }
+
+    fn read(root: PathBuf) -> Option<Self> {
+        let output = Command::new("git")
+            .arg("-C")
+            .arg(&root)
+            .args(["status", "--porcelain=v1", "-z", "--ignored=matching"])
+            .stderr(Stdio::null())
+            .output()
+            .ok()
+            .filter(|output| output.status.success())?;
+
+        let mut statuses = BTreeMap::new();
+        let mut records = output.stdout.split(|c| *c == 0);
+        while let Some(record) = records.next() {
+            let record = String::from_utf8_lossy(record);
+            let mut chars = record.chars();
+            let (Some(index), Some(worktree), Some(' ')) = (chars.next(), chars.next(), chars.next()) else {
+                continue;
+            };
+
+            // Renames and copies are followed by the original path
+            if "RC".contains(index) {
+                records.next();
+            }
+
+            statuses.insert(chars.as_str().to_string(), [index, worktree]);
+        }
+
+        Some(Self { root, statuses })
+    }
+
+    // Two characters, `-` where nothing changed. Directories show the first
+    // change found below them.
+    pub fn get(&self, path: &Path, is_dir: bool) -> [char; 2] {
+        let display = |[index, worktree]: [char; 2]| match (index, worktree) {
+            ('!', '!') => ['-', 'I'],
+            ('?', '?') => ['?', '?'],
+            (index, worktree) => [
+                if index == ' ' { '-' } else { index },
+                if worktree == ' ' { '-' } else { worktree }
+            ]
+        };
+
+        let Some(rel) = path.strip_prefix(&self.root).ok().and_then(|rel| rel.to_str()) else {
+            return ['-', '-'];
+        };
+        if let Some(status) = self.statuses.get(rel) {
+            return display(*status);
+        }
+
+        let as_dir = format!("{}/", rel);
+        for (key, status) in &self.statuses {
+            let inside = key.ends_with('/') && rel.starts_with(key.as_str());
+            if inside || (is_dir && key.starts_with(&as_dir)) {
+                return display(*status);
+            }
+        }
+
+        ['-', '-']
+    }
+}
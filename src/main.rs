@@ -1,16 +1,28 @@
 use std::path::PathBuf;
 use std::env;
+use std::process::ExitCode;
 
-use files::FilesList;
-use params::Params;
+use files::{FilesEntry, FilesList};
+use params::{IndicatorStyle, Params};
 use error::KlsError;
 
+mod archive;
+mod caps;
+mod checksum;
 mod files;
 mod formatter;
 mod params;
 mod utils;
 mod error;
 mod colors;
+mod size;
+mod gitignore;
+mod gitstatus;
+mod mime;
+mod quote;
+mod xattr;
+#[cfg(test)]
+mod testdir;
 
 pub fn get_start_path() -> PathBuf {
     match env::args().nth(1) {
@@ -19,21 +31,166 @@ pub fn get_start_path() -> PathBuf {
     }
 }
 
-fn main() -> Result<(), KlsError> {
-    let params = Params::new()?;
-    
+fn read_lists(params: &Params) -> Result<Vec<FilesList>, KlsError> {
+    colors::init_colors(params.opts.colors_from.as_deref(), &params.opts.color_overrides);
+    error::check_strict()?;
+
+    // Like GNU ls, a symlink to a directory is listed as the directory unless
+    // -d, -l or -F ask about the link itself. -H and -L always follow it and so
+    // does a trailing slash, which makes the system resolve the link.
+    let opts = &params.opts;
+    let follow_dirs = opts.dereference
+        || opts.dereference_args
+        || !(opts.directory || opts.long_format || matches!(opts.indicator_style, IndicatorStyle::Classify));
+
+    let mut operands: Vec<FilesEntry> = vec![];
     let mut files_lists: Vec<FilesList> = vec![];
     for path in &params.paths {
-        let files_list = FilesList::new(path, &params.opts);
+        let is_dir = if follow_dirs {
+            path.is_dir()
+        } else {
+            path.symlink_metadata().is_ok_and(|meta| meta.is_dir())
+        };
 
-        if let Ok(files_list) = files_list {
-            files_lists.push(files_list);
+        // With -d, and for anything that isn't a directory, the operand itself is listed
+        let result = if params.opts.archive && !params.opts.directory && archive::is_archive(path) {
+            FilesList::from_archive(path, &params.opts).map(|files_list| files_lists.push(files_list))
+        } else if params.opts.directory || !is_dir {
+            FilesEntry::from_operand(path, params.opts.dereference || params.opts.dereference_args).map(|entry| operands.push(entry))
         } else {
-            eprintln!("kls: cannot access '{}': No such file or directory.", path.to_str().unwrap_or("Unknown path"));
+            FilesList::new(path, &params.opts).map(|files_list| files_lists.push(files_list))
+        };
+
+        if let Err(e) = result {
+            error::report(&format!("cannot access '{}'", path.to_str().unwrap_or("Unknown path")), e.as_ref());
+        }
+
+        error::check_strict()?;
+    }
+
+    if !operands.is_empty() {
+        files_lists.insert(0, FilesList::from_operands(operands, &params.opts));
+    }
+
+    Ok(files_lists)
+}
+
+fn list(params: &Params) -> Result<ExitCode, KlsError> {
+    formatter::output(read_lists(params)?, &params.opts)?;
+
+    if error::finish_report() {
+        Ok(ExitCode::FAILURE)
+    } else {
+        Ok(ExitCode::SUCCESS)
+    }
+}
+
+// Each operand is described on its own, directories aren't read
+fn stat(params: &Params) -> Result<ExitCode, KlsError> {
+    colors::init_colors(params.opts.colors_from.as_deref(), &params.opts.color_overrides);
+    error::check_strict()?;
+
+    let mut entries: Vec<FilesEntry> = vec![];
+    for path in &params.paths {
+        match FilesEntry::from_operand(path, params.opts.dereference || params.opts.dereference_args) {
+            Ok(entry) => entries.push(entry),
+            Err(e) => error::report(&format!("cannot stat '{}'", path.to_str().unwrap_or("Unknown path")), e.as_ref())
         }
+        error::check_strict()?;
     }
 
-    formatter::output(files_lists, &params.opts);
-    Ok(())
+    formatter::output_stat(&entries, &params.opts)?;
+
+    if error::finish_report() {
+        Ok(ExitCode::FAILURE)
+    } else {
+        Ok(ExitCode::SUCCESS)
+    }
 }
 
+// Exits with 0 when both directories match, 1 on differences and 2 on errors
+fn diff(params: &Params) -> Result<ExitCode, KlsError> {
+    if params.paths.len() != 2 || !params.paths.iter().all(|path| path.is_dir()) {
+        return Err(KlsError::S(String::from("--diff takes exactly two directory operands")));
+    }
+
+    let lists = read_lists(params)?;
+    let differs = match lists.as_slice() {
+        [a, b] => formatter::output_diff(a, b, &params.opts)?,
+        _ => false
+    };
+
+    if error::finish_report() {
+        Ok(ExitCode::from(2))
+    } else if differs {
+        Ok(ExitCode::FAILURE)
+    } else {
+        Ok(ExitCode::SUCCESS)
+    }
+}
+
+fn main() -> Result<ExitCode, KlsError> {
+    let params = Params::new()?;
+    error::init_report(params.opts.quiet_errors, params.opts.strict);
+
+    let result = if params.opts.diff {
+        diff(&params)
+    } else if params.opts.stat {
+        stat(&params)
+    } else {
+        list(&params)
+    };
+
+    match result {
+        Err(KlsError::S(msg)) => {
+            eprintln!("kls: {}", msg);
+            Ok(ExitCode::from(2))
+        },
+        result => result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use files::FilesType;
+    use testdir::TestDir;
+
+    fn lists(args: &[&str], paths: &[&PathBuf]) -> Vec<FilesList> {
+        let args = args
+            .iter()
+            .map(|arg| arg.to_string())
+            .chain(paths.iter().map(|path| path.to_string_lossy().into_owned()));
+        read_lists(&Params::from_args(args).unwrap()).unwrap()
+    }
+
+    fn names(list: &FilesList) -> Vec<String> {
+        list.entries.iter().map(|entry| entry.name_lossy().into_owned()).collect()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn dereference_args_follows_only_operands() {
+        let dir = TestDir::new();
+        dir.dir("target");
+        dir.file("target/inner", b"");
+        let link = dir.symlink("target", "link");
+        let dangling = dir.symlink("nowhere", "dangling");
+
+        // -l describes the link itself
+        let plain = lists(&["-l"], &[&link]);
+        assert!(plain[0].dir.is_none() && matches!(plain[0].entries[0].file_type, FilesType::Sym(..)));
+
+        // -H lists the directory behind it, a dangling operand is reported and left out
+        let followed = lists(&["-lH"], &[&link, &dangling]);
+        assert_eq!(followed.len(), 1);
+        assert!(followed[0].dir.is_some());
+        assert_eq!(names(&followed[0]), ["inner"]);
+
+        // Links inside a listed directory are not followed
+        let listed = lists(&["-lH"], &[dir.path()]);
+        assert_eq!(names(&listed[0]), ["dangling", "link", "target"]);
+        assert!(matches!(listed[0].entries[1].file_type, FilesType::Sym(..)));
+        assert!(matches!(listed[0].entries[2].file_type, FilesType::Dir(_)));
+    }
+}
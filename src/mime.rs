@@ -0,0 +1,106 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+// Media types by lowercase extension
+const EXTENSIONS: [(&str, &str); 58] = [
+    ("rs", "text/x-rust"), ("c", "text/x-c"), ("h", "text/x-c"), ("cpp", "text/x-c++"),
+    ("hpp", "text/x-c++"), ("go", "text/x-go"), ("java", "text/x-java"), ("py", "text/x-python"),
+    ("sh", "text/x-shellscript"), ("js", "text/javascript"), ("mjs", "text/javascript"), ("ts", "text/x-typescript"),
+    ("txt", "text/plain"), ("md", "text/markdown"), ("csv", "text/csv"), ("html", "text/html"),
+    ("htm", "text/html"), ("css", "text/css"), ("xml", "text/xml"), ("json", "application/json"),
+    ("toml", "application/toml"), ("yaml", "application/yaml"), ("yml", "application/yaml"), ("png", "image/png"),
+    ("jpg", "image/jpeg"), ("jpeg", "image/jpeg"), ("gif", "image/gif"), ("webp", "image/webp"),
+    ("bmp", "image/bmp"), ("svg", "image/svg+xml"), ("ico", "image/vnd.microsoft.icon"), ("tif", "image/tiff"),
+    ("tiff", "image/tiff"), ("mp3", "audio/mpeg"), ("wav", "audio/wav"), ("flac", "audio/flac"),
+    ("ogg", "audio/ogg"), ("mp4", "video/mp4"), ("mkv", "video/x-matroska"), ("webm", "video/webm"),
+    ("pdf", "application/pdf"), ("zip", "application/zip"), ("gz", "application/gzip"), ("tgz", "application/gzip"),
+    ("tar", "application/x-tar"), ("bz2", "application/x-bzip2"), ("xz", "application/x-xz"), ("zst", "application/zstd"),
+    ("7z", "application/x-7z-compressed"), ("rar", "application/vnd.rar"), ("iso", "application/x-iso9660-image"), ("deb", "application/vnd.debian.binary-package"),
+    ("rpm", "application/x-rpm"), ("exe", "application/vnd.microsoft.portable-executable"), ("dll", "application/vnd.microsoft.portable-executable"), ("wasm", "application/wasm"),
+    ("sqlite", "application/vnd.sqlite3"), ("db", "application/vnd.sqlite3")
+];
+
+// Signatures at the start of the file
+const MAGIC: [(&[u8], &str); 22] = [
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"BM", "image/bmp"),
+    (b"%PDF-", "application/pdf"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"PK\x05\x06", "application/zip"),
+    (b"\x1f\x8b", "application/gzip"),
+    (b"BZh", "application/x-bzip2"),
+    (b"\xfd7zXZ\0", "application/x-xz"),
+    (b"\x28\xb5\x2f\xfd", "application/zstd"),
+    (b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+    (b"Rar!\x1a\x07", "application/vnd.rar"),
+    (b"\x7fELF", "application/x-executable"),
+    (b"MZ", "application/vnd.microsoft.portable-executable"),
+    (b"\0asm", "application/wasm"),
+    (b"SQLite format 3\0", "application/vnd.sqlite3"),
+    (b"OggS", "audio/ogg"),
+    (b"fLaC", "audio/flac"),
+    (b"ID3", "audio/mpeg"),
+    (b"<?xml", "text/xml")
+];
+
+// Detected from the extension, or from the first bytes of the file when the
+// extension is missing or unknown. None when the file can't be read.
+pub fn detect(path: &Path, extension: &str) -> Option<&'static str> {
+    if let Some(mime) = from_extension(extension) {
+        return Some(mime);
+    }
+
+    let mut head = vec![];
+    File::open(path).ok()?.take(512).read_to_end(&mut head).ok()?;
+    Some(sniff(&head))
+}
+
+// The extension alone, for files that can't be opened like archive members
+pub fn from_extension(extension: &str) -> Option<&'static str> {
+    let extension = extension.to_lowercase();
+    EXTENSIONS.iter().find(|(ext, _)| *ext == extension).map(|(_, mime)| *mime)
+}
+
+fn sniff(head: &[u8]) -> &'static str {
+    if head.is_empty() {
+        return "inode/x-empty";
+    }
+    if let Some((_, mime)) = MAGIC.iter().find(|(magic, _)| head.starts_with(magic)) {
+        return mime;
+    }
+    if head.len() >= 12 && &head[..4] == b"RIFF" && &head[8..12] == b"WEBP" {
+        return "image/webp";
+    }
+    if head.len() >= 262 && &head[257..262] == b"ustar" {
+        return "application/x-tar";
+    }
+
+    // The prefix may end halfway through a character, so only earlier errors count
+    let text = match std::str::from_utf8(head) {
+        Ok(text) => text,
+        Err(e) if e.error_len().is_none() => std::str::from_utf8(&head[..e.valid_up_to()]).unwrap_or_default(),
+        Err(_) => return "application/octet-stream"
+    };
+    if text.contains('\0') {
+        return "application/octet-stream";
+    }
+
+    let first_line = text.lines().next().unwrap_or_default();
+    if let Some(interpreter) = first_line.strip_prefix("#!") {
+        if interpreter.contains("python") {
+            return "text/x-python";
+        } else if interpreter.contains("sh") {
+            return "text/x-shellscript";
+        }
+    }
+    let start = text.trim_start().to_lowercase();
+    if start.starts_with("<!doctype html") || start.starts_with("<html") {
+        return "text/html";
+    }
+
+    "text/plain"
+}
@@ -1,11 +1,235 @@
 use std::env;
+use std::io::{self, IsTerminal};
 use std::path::PathBuf;
 use crate::utils::StrUtil;
 use crate::error::KlsError;
+use crate::colors::parse_color_entry;
+use crate::size::BlockSize;
+use chrono::format::{Item, StrftimeItems};
+
+pub enum GroupBy {
+    None,
+    Type
+}
+
+pub enum Only {
+    All,
+    Dirs,
+    Files
+}
+
+pub enum SortMode {
+    Name,
+    Time,
+    Size,
+    Extension,
+    Version,
+    None,
+    // --sort=KEY,KEY,... with later keys breaking ties, true where `-` reverses a key
+    Keys(Vec<(SortMode, bool)>)
+}
+
+pub enum TimeStyle {
+    Default,
+    Iso,
+    LongIso,
+    FullIso,
+    // strftime patterns for old and recent times
+    Custom(String, String),
+    Relative
+}
+
+// Name ordering, picked from LC_ALL, LC_COLLATE or LANG like GNU ls
+pub enum Collation {
+    Bytes,
+    Locale
+}
+
+impl Collation {
+    pub fn from_locale(locale: &str) -> Self {
+        match locale {
+            "" | "C" | "POSIX" => Self::Bytes,
+            l if l.starts_with("C.") => Self::Bytes,
+            _ => Self::Locale
+        }
+    }
+
+    fn from_env() -> Self {
+        let locale = ["LC_ALL", "LC_COLLATE", "LANG"]
+            .iter()
+            .filter_map(|key| env::var(key).ok())
+            .find(|value| !value.is_empty())
+            .unwrap_or_default();
+        Self::from_locale(&locale)
+    }
+}
+
+pub enum OctalPermissions {
+    Off,
+    Both,
+    Replace
+}
+
+// The last of -p and -F wins, like GNU ls
+pub enum IndicatorStyle {
+    None,
+    Slash,
+    FileType,
+    Classify
+}
+
+// How short listings are laid out, -l takes precedence
+pub enum Layout {
+    // -1, the default when piped
+    OnePerLine,
+    // -C, names down the columns of a grid sized to the terminal
+    Columns,
+    // -x, the same grid filled across the rows
+    Across,
+    // -m, comma separated and wrapped to the terminal
+    Commas
+}
+
+pub enum Checksum {
+    Md5,
+    Sha256,
+    Blake3
+}
+
+pub enum QuotingStyle {
+    Literal,
+    Shell,
+    ShellAlways,
+    ShellEscape,
+    ShellEscapeAlways,
+    Escape,
+    C
+}
+
+// Long format columns, see `Column::NAMES` for the --fields spelling
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Column {
+    Inode,
+    Blocks,
+    Octal,
+    Mode,
+    Links,
+    User,
+    Group,
+    Flags,
+    Author,
+    Context,
+    Caps,
+    Size,
+    Modified,
+    Git,
+    Mime,
+    Checksum,
+    Name
+}
+
+impl Column {
+    pub const NAMES: [(&'static str, Column); 17] = [
+        ("inode", Column::Inode),
+        ("blocks", Column::Blocks),
+        ("octal", Column::Octal),
+        ("permissions", Column::Mode),
+        ("links", Column::Links),
+        ("user", Column::User),
+        ("group", Column::Group),
+        ("flags", Column::Flags),
+        ("author", Column::Author),
+        ("context", Column::Context),
+        ("caps", Column::Caps),
+        ("size", Column::Size),
+        ("modified", Column::Modified),
+        ("git", Column::Git),
+        ("mime", Column::Mime),
+        ("checksum", Column::Checksum),
+        ("name", Column::Name)
+    ];
+}
+
+pub enum TimeField {
+    Modified,
+    Accessed,
+    Changed,
+    Birth
+}
 
 pub struct Opts {
     pub long_format: bool,
-    pub all_files: bool
+    pub all_files: bool,
+    pub almost_all: bool,
+    pub ignore_backups: bool,
+    pub ignore: Vec<String>,
+    pub hide: Vec<String>,
+    pub directory: bool,
+    pub only: Only,
+    pub recursive: bool,
+    pub tree: bool,
+    pub level: Option<usize>,
+    pub dereference: bool,
+    pub dereference_args: bool,
+    pub one_file_system: bool,
+    pub git_ignore: bool,
+    pub git: bool,
+    pub glob: Vec<String>,
+    pub iglob: Vec<String>,
+    pub inode: bool,
+    pub size_blocks: bool,
+    pub numeric_ids: bool,
+    pub show_owner: bool,
+    pub show_group: bool,
+    pub smart_group: bool,
+    pub show_permissions: bool,
+    pub show_size: bool,
+    pub show_time: bool,
+    // The `total` line above directory listings
+    pub show_total: bool,
+    pub author: bool,
+    pub context: bool,
+    pub flags: bool,
+    pub xattr_detail: bool,
+    pub caps: bool,
+    pub hardlinks: bool,
+    pub sparse: bool,
+    pub octal_permissions: OctalPermissions,
+    pub block_size: Option<BlockSize>,
+    pub kibibytes: bool,
+    pub size_grouping: Option<String>,
+    pub time_style: TimeStyle,
+    pub indicator_style: IndicatorStyle,
+    pub layout: Layout,
+    // Line width for -C, -x and -m, 0 for no limit. None asks the terminal.
+    pub width: Option<usize>,
+    // -T, pad the grid with tabs this far apart where they fit, 0 for spaces only
+    pub tab_size: usize,
+    // --grid, long rows side by side when more than one fits the line
+    pub grid: bool,
+    pub quoting_style: QuotingStyle,
+    pub hide_control_chars: bool,
+    pub quiet_errors: bool,
+    pub strict: bool,
+    pub colors_from: Option<PathBuf>,
+    pub hidden_hint: bool,
+    pub dir_headers: bool,
+    pub header: bool,
+    pub fields: Option<Vec<Column>>,
+    pub group_by: GroupBy,
+    pub diff: bool,
+    pub stat: bool,
+    pub archive: bool,
+    pub mime: bool,
+    pub checksum: Option<Checksum>,
+    // Larger files aren't hashed, None hashes everything
+    pub checksum_limit: Option<u64>,
+    pub color_overrides: Vec<(String, String)>,
+    pub sort: SortMode,
+    pub reverse: bool,
+    pub group_directories_first: bool,
+    pub time: TimeField,
+    pub collation: Collation
 }
 
 pub struct Params {
@@ -15,46 +239,566 @@ pub struct Params {
 
 impl Params {
     pub fn new() -> Result<Self, KlsError> {
+        Self::from_args(env::args().skip(1))
+    }
+
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<Self, KlsError> {
         let mut params = Self {
             paths: vec![],
             opts: Opts {
                 long_format: false,
-                all_files: false
+                all_files: false,
+                almost_all: false,
+                ignore_backups: false,
+                ignore: vec![],
+                hide: vec![],
+                directory: false,
+                only: Only::All,
+                recursive: false,
+                tree: false,
+                level: None,
+                dereference: false,
+                dereference_args: false,
+                one_file_system: false,
+                git_ignore: false,
+                git: false,
+                glob: vec![],
+                iglob: vec![],
+                inode: false,
+                size_blocks: false,
+                numeric_ids: false,
+                show_owner: true,
+                show_group: true,
+                smart_group: false,
+                hardlinks: false,
+                sparse: false,
+                show_permissions: true,
+                show_size: true,
+                show_time: true,
+                show_total: true,
+                author: false,
+                context: false,
+                flags: false,
+                xattr_detail: false,
+                caps: false,
+                octal_permissions: OctalPermissions::Off,
+                block_size: None,
+                kibibytes: false,
+                size_grouping: None,
+                time_style: TimeStyle::Default,
+                indicator_style: IndicatorStyle::None,
+                layout: if io::stdout().is_terminal() { Layout::Columns } else { Layout::OnePerLine },
+                width: None,
+                tab_size: 0,
+                grid: false,
+                // Copy-pasteable names on a terminal, like GNU ls
+                quoting_style: if io::stdout().is_terminal() { QuotingStyle::ShellEscape } else { QuotingStyle::Literal },
+                // Like GNU ls, names can't mess with the terminal unless piped
+                hide_control_chars: io::stdout().is_terminal(),
+                quiet_errors: false,
+                strict: false,
+                colors_from: None,
+                hidden_hint: io::stdout().is_terminal(),
+                dir_headers: true,
+                header: false,
+                fields: None,
+                group_by: GroupBy::None,
+                diff: false,
+                stat: false,
+                archive: false,
+                mime: false,
+                checksum: None,
+                checksum_limit: Some(1 << 30),
+                color_overrides: vec![],
+                sort: SortMode::Name,
+                reverse: false,
+                group_directories_first: false,
+                time: TimeField::Modified,
+                collation: Collation::from_env()
             }
         };
 
-        let mut args = env::args().skip(1);
+        let mut args = args.into_iter();
+        let mut sort_specified = false;
 
         while let Some(arg) = args.next() {
             if arg.starts_with("--") {
                 let arg = arg.substr_after(2);
+                let (arg, value) = match arg.find('=') {
+                    Some(i) => (arg[..i].to_string(), Some(arg[i + 1..].to_string())),
+                    None => (arg, None)
+                };
 
                 if arg == "long-format" {
                     params.opts.long_format = true;
-                } else if arg == "-all" {
+                } else if arg == "all" {
                     params.opts.all_files = true;
+                    params.opts.almost_all = false;
+                } else if arg == "almost-all" {
+                    params.opts.almost_all = true;
+                    params.opts.all_files = false;
+                } else if arg == "ignore-backups" {
+                    params.opts.ignore_backups = true;
+                } else if arg == "ignore" {
+                    params.opts.ignore.push(Self::get_value(&arg, value, &mut args)?);
+                } else if arg == "hide" {
+                    params.opts.hide.push(Self::get_value(&arg, value, &mut args)?);
+                } else if arg == "directory" {
+                    params.opts.directory = true;
+                } else if arg == "only-dirs" || arg == "only-files" {
+                    let only = if arg == "only-dirs" { Only::Dirs } else { Only::Files };
+                    if matches!((&params.opts.only, &only), (Only::Dirs, Only::Files) | (Only::Files, Only::Dirs)) {
+                        return Err(KlsError::S(String::from("--only-dirs and --only-files are mutually exclusive")));
+                    }
+                    params.opts.only = only;
+                } else if arg == "recursive" {
+                    params.opts.recursive = true;
+                } else if arg == "tree" {
+                    params.opts.tree = true;
+                } else if arg == "level" {
+                    let value = Self::get_value(&arg, value, &mut args)?;
+                    params.opts.level = Some(value
+                        .parse::<usize>()
+                        .map_err(|_| KlsError::S(format!("Invalid level '{}' for --level, expected a non-negative number", value)))?);
+                } else if arg == "width" {
+                    params.opts.width = Some(Self::parse_width(&Self::get_value(&arg, value, &mut args)?)?);
+                } else if arg == "grid" {
+                    // Like -C on its own
+                    params.opts.grid = true;
+                    params.opts.layout = Layout::Columns;
+                } else if arg == "tabsize" {
+                    params.opts.tab_size = Self::parse_tab_size(&Self::get_value(&arg, value, &mut args)?)?;
+                } else if arg == "dereference" {
+                    params.opts.dereference = true;
+                } else if arg == "dereference-command-line" {
+                    params.opts.dereference_args = true;
+                } else if arg == "one-file-system" {
+                    params.opts.one_file_system = true;
+                } else if arg == "git-ignore" {
+                    params.opts.git_ignore = true;
+                } else if arg == "git" {
+                    params.opts.git = true;
+                } else if arg == "glob" {
+                    params.opts.glob.push(Self::get_value(&arg, value, &mut args)?);
+                } else if arg == "iglob" {
+                    params.opts.iglob.push(Self::get_value(&arg, value, &mut args)?.to_lowercase());
+                } else if arg == "inode" {
+                    params.opts.inode = true;
+                } else if arg == "size" {
+                    params.opts.size_blocks = true;
+                } else if arg == "numeric-uid-gid" {
+                    params.opts.numeric_ids = true;
+                    params.opts.long_format = true;
+                } else if arg == "sparse" {
+                    params.opts.sparse = true;
+                } else if arg == "hardlinks" {
+                    params.opts.hardlinks = true;
+                } else if arg == "smart-group" {
+                    params.opts.smart_group = true;
+                } else if arg == "no-permissions" {
+                    params.opts.show_permissions = false;
+                } else if arg == "no-user" {
+                    params.opts.show_owner = false;
+                } else if arg == "no-filesize" {
+                    params.opts.show_size = false;
+                } else if arg == "no-time" {
+                    params.opts.show_time = false;
+                } else if arg == "no-total" {
+                    params.opts.show_total = false;
+                } else if arg == "author" {
+                    params.opts.author = true;
+                } else if arg == "caps" {
+                    params.opts.caps = true;
+                } else if arg == "xattr-detail" {
+                    params.opts.xattr_detail = true;
+                } else if arg == "flags" {
+                    params.opts.flags = true;
+                } else if arg == "context" {
+                    params.opts.context = true;
+                } else if arg == "octal-permissions" {
+                    // The value is optional, so it's never taken from the next argument
+                    params.opts.octal_permissions = match value.as_deref() {
+                        None | Some("both") => OctalPermissions::Both,
+                        Some("replace") => OctalPermissions::Replace,
+                        Some(other) => return Err(Self::invalid_value(&arg, other, &["both", "replace"]))
+                    };
+                } else if arg == "human-readable" {
+                    params.opts.block_size = Some(BlockSize::Human);
+                } else if arg == "si" {
+                    params.opts.block_size = Some(BlockSize::Si);
+                } else if arg == "block-size" {
+                    params.opts.block_size = Some(BlockSize::parse(&Self::get_value(&arg, value, &mut args)?)?);
+                } else if arg == "kibibytes" {
+                    params.opts.kibibytes = true;
+                } else if arg == "size-grouping" {
+                    // The separator is optional, so it's never taken from the next argument
+                    params.opts.size_grouping = Some(value.unwrap_or_else(|| String::from(",")));
+                } else if arg == "full-time" {
+                    params.opts.long_format = true;
+                    params.opts.time_style = TimeStyle::FullIso;
+                } else if arg == "time-style" {
+                    params.opts.time_style = match Self::get_value(&arg, value, &mut args)?.as_str() {
+                        custom if custom.starts_with('+') => Self::parse_time_format(&custom[1..])?,
+                        "locale" => TimeStyle::Default,
+                        "iso" => TimeStyle::Iso,
+                        "long-iso" => TimeStyle::LongIso,
+                        "full-iso" => TimeStyle::FullIso,
+                        "relative" => TimeStyle::Relative,
+                        other => return Err(Self::invalid_value(&arg, other, &["full-iso", "long-iso", "iso", "locale", "relative", "+FORMAT"]))
+                    };
+                } else if arg == "time" {
+                    params.opts.time = match Self::get_value(&arg, value, &mut args)?.as_str() {
+                        "mtime" | "modification" => TimeField::Modified,
+                        "atime" | "access" | "use" => TimeField::Accessed,
+                        "ctime" | "status" => TimeField::Changed,
+                        "birth" | "creation" => TimeField::Birth,
+                        other => return Err(Self::invalid_value(&arg, other, &["mtime", "atime", "ctime", "birth"]))
+                    };
+                } else if arg == "classify" {
+                    // The value is optional, so it's never taken from the next argument
+                    let classify = match value.as_deref() {
+                        None | Some("always" | "yes" | "force") => true,
+                        Some("never" | "no" | "none") => false,
+                        Some("auto" | "tty" | "if-tty") => io::stdout().is_terminal(),
+                        Some(other) => return Err(Self::invalid_value(&arg, other, &["always", "auto", "never"]))
+                    };
+                    params.opts.indicator_style = if classify { IndicatorStyle::Classify } else { IndicatorStyle::None };
+                } else if arg == "file-type" {
+                    params.opts.indicator_style = IndicatorStyle::FileType;
+                } else if arg == "indicator-style" {
+                    params.opts.indicator_style = match Self::get_value(&arg, value, &mut args)?.as_str() {
+                        "none" => IndicatorStyle::None,
+                        "slash" => IndicatorStyle::Slash,
+                        "file-type" => IndicatorStyle::FileType,
+                        "classify" => IndicatorStyle::Classify,
+                        other => return Err(Self::invalid_value(&arg, other, &["none", "slash", "file-type", "classify"]))
+                    };
+                } else if arg == "escape" {
+                    params.opts.quoting_style = QuotingStyle::Escape;
+                } else if arg == "hide-control-chars" {
+                    params.opts.hide_control_chars = true;
+                } else if arg == "show-control-chars" {
+                    params.opts.hide_control_chars = false;
+                } else if arg == "quoting-style" {
+                    params.opts.quoting_style = match Self::get_value(&arg, value, &mut args)?.as_str() {
+                        "literal" => QuotingStyle::Literal,
+                        "shell" => QuotingStyle::Shell,
+                        "shell-always" => QuotingStyle::ShellAlways,
+                        "shell-escape" => QuotingStyle::ShellEscape,
+                        "shell-escape-always" => QuotingStyle::ShellEscapeAlways,
+                        "c" => QuotingStyle::C,
+                        "escape" => QuotingStyle::Escape,
+                        other => return Err(Self::invalid_value(&arg, other, &["literal", "shell", "shell-always", "shell-escape", "shell-escape-always", "c", "escape"]))
+                    };
+                } else if arg == "literal" {
+                    params.opts.quoting_style = QuotingStyle::Literal;
+                    params.opts.hide_control_chars = false;
+                } else if arg == "quote-name" {
+                    params.opts.quoting_style = QuotingStyle::C;
+                } else if arg == "created" {
+                    params.opts.time = TimeField::Birth;
+                } else if arg == "quiet-errors" {
+                    params.opts.quiet_errors = true;
+                } else if arg == "verbose" {
+                    // Undoes an earlier --quiet-errors, say from an alias
+                    params.opts.quiet_errors = false;
+                } else if arg == "strict" {
+                    params.opts.strict = true;
+                } else if arg == "hidden-hint" {
+                    params.opts.hidden_hint = true;
+                } else if arg == "no-hidden-hint" {
+                    params.opts.hidden_hint = false;
+                } else if arg == "no-dir-headers" {
+                    params.opts.dir_headers = false;
+                } else if arg == "header" {
+                    params.opts.header = true;
+                } else if arg == "fields" {
+                    let mut fields = vec![];
+                    for field in Self::get_value(&arg, value, &mut args)?.split(',') {
+                        match Column::NAMES.iter().find(|(name, _)| *name == field) {
+                            Some((_, column)) => fields.push(*column),
+                            None => return Err(Self::invalid_value(&arg, field, &Column::NAMES.map(|(name, _)| name)))
+                        }
+                    }
+
+                    // These are only read when asked for
+                    params.opts.context |= fields.contains(&Column::Context);
+                    params.opts.git |= fields.contains(&Column::Git);
+                    params.opts.mime |= fields.contains(&Column::Mime);
+                    params.opts.caps |= fields.contains(&Column::Caps);
+                    if fields.contains(&Column::Checksum) && params.opts.checksum.is_none() {
+                        params.opts.checksum = Some(Checksum::Sha256);
+                    }
+                    params.opts.fields = Some(fields);
+                } else if arg == "reverse" {
+                    params.opts.reverse = true;
+                } else if arg == "group-directories-first" {
+                    params.opts.group_directories_first = true;
+                } else if arg == "checksum" {
+                    // The algorithm is optional, so it's never taken from the next argument
+                    params.opts.checksum = Some(match value.as_deref() {
+                        None | Some("sha256") => Checksum::Sha256,
+                        Some("md5") => Checksum::Md5,
+                        Some("blake3") => Checksum::Blake3,
+                        Some(other) => return Err(Self::invalid_value(&arg, other, &["sha256", "md5", "blake3"]))
+                    });
+                } else if arg == "checksum-limit" {
+                    params.opts.checksum_limit = match Self::get_value(&arg, value, &mut args)?.as_str() {
+                        "none" => None,
+                        spec => match BlockSize::parse(spec)? {
+                            BlockSize::Scaled(size, _, false) => Some(size),
+                            _ => return Err(KlsError::S(format!("Invalid size for --{}: '{}'", arg, spec)))
+                        }
+                    };
+                } else if arg == "mime" {
+                    params.opts.mime = true;
+                } else if arg == "archive" {
+                    params.opts.archive = true;
+                } else if arg == "stat" {
+                    params.opts.stat = true;
+                } else if arg == "diff" {
+                    params.opts.diff = true;
+                } else if arg == "group-by" {
+                    params.opts.group_by = match Self::get_value(&arg, value, &mut args)?.as_str() {
+                        "none" => GroupBy::None,
+                        "type" => GroupBy::Type,
+                        other => return Err(Self::invalid_value(&arg, other, &["none", "type"]))
+                    };
+                } else if arg == "sort" {
+                    let value = Self::get_value(&arg, value, &mut args)?;
+                    let mut keys = vec![];
+                    for key in value.split(',') {
+                        let (reversed, key) = match key.strip_prefix('-') {
+                            Some(key) => (true, key),
+                            None => (false, key)
+                        };
+                        let mode = match key {
+                            "name" => SortMode::Name,
+                            "size" => SortMode::Size,
+                            "time" => SortMode::Time,
+                            "extension" | "ext" => SortMode::Extension,
+                            "version" => SortMode::Version,
+                            "none" if value == "none" => SortMode::None,
+                            "created" => {
+                                params.opts.time = TimeField::Birth;
+                                SortMode::Time
+                            },
+                            other => return Err(Self::invalid_value(&arg, other, &["name", "size", "time", "extension", "version", "none", "created"]))
+                        };
+                        keys.push((mode, reversed));
+                    }
+
+                    // A single key sorts just like the matching short flag
+                    params.opts.sort = match keys.pop() {
+                        Some((mode, false)) if keys.is_empty() => mode,
+                        Some(last) => {
+                            keys.push(last);
+                            SortMode::Keys(keys)
+                        },
+                        None => SortMode::Name
+                    };
+                    sort_specified = true;
+                } else if arg == "color-override" {
+                    params.opts.color_overrides.push(parse_color_entry(&Self::get_value(&arg, value, &mut args)?)?);
+                } else if arg == "colors-from" {
+                    params.opts.colors_from = Some(PathBuf::from(Self::get_value(&arg, value, &mut args)?));
                 } else {
                     return Err(KlsError::S(format!("Unknown argument: --{}", arg)));
                 }
             } else if arg.starts_with("-") {
-                let arg = arg.substr_after(1);
+                let flags = arg.substr_after(1);
+                let mut chars = flags.chars();
+                while let Some(c) = chars.next() {
+                    match c {
+                        'l' => params.opts.long_format = true,
+                        // The last of -l, -1, -C, -x and -m wins, like GNU ls
+                        '1' => {
+                            params.opts.layout = Layout::OnePerLine;
+                            params.opts.long_format = false;
+                        },
+                        'C' => {
+                            params.opts.layout = Layout::Columns;
+                            params.opts.long_format = false;
+                        },
+                        'x' => {
+                            params.opts.layout = Layout::Across;
+                            params.opts.long_format = false;
+                        },
+                        'm' => {
+                            params.opts.layout = Layout::Commas;
+                            params.opts.long_format = false;
+                        },
+                        'a' => {
+                            params.opts.all_files = true;
+                            params.opts.almost_all = false;
+                        },
+                        'A' => {
+                            params.opts.almost_all = true;
+                            params.opts.all_files = false;
+                        },
+                        't' => params.opts.sort = SortMode::Time,
+                        'u' => params.opts.time = TimeField::Accessed,
+                        'c' => params.opts.time = TimeField::Changed,
+                        'S' => params.opts.sort = SortMode::Size,
+                        'X' => params.opts.sort = SortMode::Extension,
+                        'v' => params.opts.sort = SortMode::Version,
+                        'U' => params.opts.sort = SortMode::None,
+                        'r' => params.opts.reverse = true,
+                        'B' => params.opts.ignore_backups = true,
+                        'd' => params.opts.directory = true,
+                        'R' => params.opts.recursive = true,
+                        'L' => params.opts.dereference = true,
+                        'H' => params.opts.dereference_args = true,
+                        'i' => params.opts.inode = true,
+                        's' => params.opts.size_blocks = true,
+                        'g' => {
+                            params.opts.show_owner = false;
+                            params.opts.long_format = true;
+                        },
+                        'h' => params.opts.block_size = Some(BlockSize::Human),
+                        'k' => params.opts.kibibytes = true,
+                        'Z' => params.opts.context = true,
+                        'O' => params.opts.flags = true,
+                        '@' => params.opts.xattr_detail = true,
+                        'F' => params.opts.indicator_style = IndicatorStyle::Classify,
+                        'p' => params.opts.indicator_style = IndicatorStyle::Slash,
+                        'Q' => params.opts.quoting_style = QuotingStyle::C,
+                        'b' => params.opts.quoting_style = QuotingStyle::Escape,
+                        'q' => params.opts.hide_control_chars = true,
+                        // Names exactly as stored, whatever was picked before
+                        'N' => {
+                            params.opts.quoting_style = QuotingStyle::Literal;
+                            params.opts.hide_control_chars = false;
+                        },
+                        'o' => {
+                            params.opts.show_group = false;
+                            params.opts.long_format = true;
+                        },
+                        'n' => {
+                            params.opts.numeric_ids = true;
+                            params.opts.long_format = true;
+                        },
+                        'I' => {
+                            // The pattern is either the rest of this argument or the next one
+                            let rest = chars.as_str();
+                            let value = (!rest.is_empty()).then(|| rest.to_string());
+                            params.opts.ignore.push(Self::get_value("ignore", value, &mut args)?);
+                            break;
+                        },
+                        'w' => {
+                            let rest = chars.as_str();
+                            let value = (!rest.is_empty()).then(|| rest.to_string());
+                            params.opts.width = Some(Self::parse_width(&Self::get_value("width", value, &mut args)?)?);
+                            break;
+                        },
+                        'T' => {
+                            let rest = chars.as_str();
+                            let value = (!rest.is_empty()).then(|| rest.to_string());
+                            params.opts.tab_size = Self::parse_tab_size(&Self::get_value("tabsize", value, &mut args)?)?;
+                            break;
+                        },
+                        _ => return Err(KlsError::S(format!("Unknown argument: -{}", c)))
+                    }
 
-                if arg.contains('l') {
-                    params.opts.long_format = true;
-                }
-
-                if arg.contains('a') {
-                    params.opts.all_files = true;
+                    if "tSXvU".contains(c) {
+                        sort_specified = true;
+                    }
                 }
             } else {
                 params.paths.push(PathBuf::from(arg));
             }
         }
 
+        // Like GNU ls, -u or -c without -l sorts by that time unless a sort was given
+        if !matches!(params.opts.time, TimeField::Modified) && !params.opts.long_format && !sort_specified {
+            params.opts.sort = SortMode::Time;
+        }
+
         if params.paths.is_empty() {
-            params.paths.push(PathBuf::from("./"));
+            params.paths.push(PathBuf::from("."));
         }
 
         Ok(params)
     }
+
+    // `+FORMAT` or GNU's `+OLD\nRECENT` pair, checked once here since chrono
+    // only reports bad patterns when formatting
+    fn parse_time_format(format: &str) -> Result<TimeStyle, KlsError> {
+        let (old, recent) = format.split_once('\n').unwrap_or((format, format));
+        for pattern in [old, recent] {
+            if StrftimeItems::new(pattern).any(|item| matches!(item, Item::Error)) {
+                return Err(KlsError::S(format!("Invalid time format: '{}'", pattern)));
+            }
+        }
+
+        Ok(TimeStyle::Custom(old.to_string(), recent.to_string()))
+    }
+
+    fn invalid_value(name: &str, value: &str, valid: &[&str]) -> KlsError {
+        KlsError::S(format!("Invalid argument '{}' for --{}, valid arguments are: {}", value, name, valid.join(", ")))
+    }
+
+    fn parse_width(value: &str) -> Result<usize, KlsError> {
+        value
+            .parse::<usize>()
+            .map_err(|_| KlsError::S(format!("Invalid line width '{}' for --width, expected a non-negative number", value)))
+    }
+
+    fn parse_tab_size(value: &str) -> Result<usize, KlsError> {
+        value
+            .parse::<usize>()
+            .map_err(|_| KlsError::S(format!("Invalid tab size '{}' for --tabsize, expected a non-negative number", value)))
+    }
+
+    fn get_value(name: &str, value: Option<String>, args: &mut impl Iterator<Item = String>) -> Result<String, KlsError> {
+        value
+            .or_else(|| args.next())
+            .ok_or_else(|| KlsError::S(format!("Option --{} requires an argument", name)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Params, KlsError> {
+        Params::from_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn single_sort_key_matches_short_flag() {
+        assert!(matches!(parse(&["--sort=size"]).unwrap().opts.sort, SortMode::Size));
+        assert!(matches!(parse(&["--sort", "version"]).unwrap().opts.sort, SortMode::Version));
+        assert!(matches!(parse(&["--sort=none"]).unwrap().opts.sort, SortMode::None));
+    }
+
+    #[test]
+    fn composite_sort_keys() {
+        let opts = parse(&["--sort=ext,-size,name"]).unwrap().opts;
+        let SortMode::Keys(keys) = opts.sort else {
+            panic!("expected a list of keys");
+        };
+        assert_eq!(keys.len(), 3);
+        assert!(matches!(keys[0], (SortMode::Extension, false)));
+        assert!(matches!(keys[1], (SortMode::Size, true)));
+        assert!(matches!(keys[2], (SortMode::Name, false)));
+
+        // A lone reversed key still needs the list to carry the `-`
+        assert!(matches!(parse(&["--sort=-name"]).unwrap().opts.sort, SortMode::Keys(ref keys) if keys.len() == 1));
+    }
+
+    #[test]
+    fn invalid_sort_keys() {
+        assert!(parse(&["--sort=size,bogus"]).is_err());
+        assert!(parse(&["--sort=none,name"]).is_err());
+        assert!(parse(&["--sort=name,"]).is_err());
+    }
+
+    #[test]
+    fn invalid_levels() {
+        assert_eq!(parse(&["--level=2"]).unwrap().opts.level, Some(2));
+        for level in ["--level=-1", "--level=x", "--level="] {
+            assert!(parse(&[level]).is_err(), "{} should be rejected", level);
+        }
+    }
 }
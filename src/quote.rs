@@ -0,0 +1,187 @@
+use std::ffi::OsStr;
+
+use crate::params::{Opts, QuotingStyle};
+
+// Renders a file name or symlink target the way the quoting style asks for.
+// Works on the raw bytes so names that aren't UTF-8 can still be escaped.
+pub fn quote(name: &OsStr, opts: &Opts) -> String {
+    let bytes = name.as_encoded_bytes();
+    match &opts.quoting_style {
+        QuotingStyle::Literal if opts.hide_control_chars => hide_control_chars(bytes),
+        QuotingStyle::Literal => String::from_utf8_lossy(bytes).into_owned(),
+        QuotingStyle::Shell => shell_quote(bytes, false, false, opts.hide_control_chars),
+        QuotingStyle::ShellAlways => shell_quote(bytes, true, false, opts.hide_control_chars),
+        QuotingStyle::ShellEscape => shell_quote(bytes, false, true, false),
+        QuotingStyle::ShellEscapeAlways => shell_quote(bytes, true, true, false),
+        style @ QuotingStyle::Escape => escape(bytes, style),
+        style @ QuotingStyle::C => format!("\"{}\"", escape(bytes, style))
+    }
+}
+
+// -q, each nongraphic character or invalid byte becomes a `?`
+fn hide_control_chars(bytes: &[u8]) -> String {
+    let mut result = String::new();
+    for chunk in bytes.utf8_chunks() {
+        result.extend(chunk.valid().chars().map(|c| if c.is_control() { '?' } else { c }));
+        result += &"?".repeat(chunk.invalid().len());
+    }
+    result
+}
+
+// Characters that need quoting anywhere in a name, `#` and `~` only at its start
+const SHELL_SPECIAL: &str = " \t\n!\"$&'()*;<=>?[\\^`|";
+// What can't go inside double quotes, which are used for names whose only problem is a `'`
+const DOUBLE_QUOTE_UNSAFE: &str = "!\"$\\`";
+
+// POSIX shell quoting like GNU ls: names are single quoted when needed, with
+// `'\''` for embedded quotes. The escape variants close the quotes around
+// $'...' sequences for nongraphic characters, the others print them as is or
+// as `?` with -q.
+fn shell_quote(bytes: &[u8], always: bool, escape_nongraphic: bool, hide_control_chars: bool) -> String {
+    // Err holds a nongraphic character, kept as its bytes
+    let mut chars: Vec<Result<char, Vec<u8>>> = vec![];
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            chars.push(match c {
+                c if c.is_control() && escape_nongraphic => {
+                    let mut buf = [0; 4];
+                    Err(c.encode_utf8(&mut buf).as_bytes().to_vec())
+                },
+                c if c.is_control() && hide_control_chars => Ok('?'),
+                c => Ok(c)
+            });
+        }
+        for b in chunk.invalid() {
+            chars.push(match () {
+                _ if escape_nongraphic => Err(vec![*b]),
+                _ if hide_control_chars => Ok('?'),
+                _ => Ok(char::REPLACEMENT_CHARACTER)
+            });
+        }
+    }
+
+    let needs_quotes = always || chars.is_empty() || chars.iter().enumerate().any(|(i, c)| match c {
+        Ok(c) => SHELL_SPECIAL.contains(*c) || (i == 0 && (*c == '#' || *c == '~')),
+        Err(_) => true
+    });
+    if !needs_quotes {
+        return chars.into_iter().flatten().collect();
+    }
+
+    let only_single_quotes = chars.iter().all(|c| matches!(c, Ok(c) if !DOUBLE_QUOTE_UNSAFE.contains(*c)));
+    if only_single_quotes && chars.contains(&Ok('\'')) {
+        return format!("\"{}\"", chars.into_iter().flatten().collect::<String>());
+    }
+
+    let mut result = String::from("'");
+    let mut iter = chars.iter().peekable();
+    while let Some(c) = iter.next() {
+        match c {
+            Ok('\'') => result += "'\\''",
+            Ok(c) => result.push(*c),
+            Err(bytes) => {
+                result += "'$'";
+                result += &escape(bytes, &QuotingStyle::C);
+                result.push('\'');
+                // Reopened only when something follows, like GNU ls
+                if iter.peek().is_none() {
+                    return result;
+                }
+                result.push('\'');
+            }
+        }
+    }
+    result.push('\'');
+    result
+}
+
+// C-style backslash escapes, with octal for anything else that isn't printable
+fn escape(bytes: &[u8], style: &QuotingStyle) -> String {
+    let mut result = String::new();
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '\\' => result += "\\\\",
+                '"' if matches!(style, QuotingStyle::C) => result += "\\\"",
+                ' ' if matches!(style, QuotingStyle::Escape) => result += "\\ ",
+                '\x07' => result += "\\a",
+                '\x08' => result += "\\b",
+                '\t' => result += "\\t",
+                '\n' => result += "\\n",
+                '\x0b' => result += "\\v",
+                '\x0c' => result += "\\f",
+                '\r' => result += "\\r",
+                c if c.is_control() => {
+                    let mut buf = [0; 4];
+                    result += &octal(c.encode_utf8(&mut buf).as_bytes());
+                },
+                c => result.push(c)
+            }
+        }
+        result += &octal(chunk.invalid());
+    }
+    result
+}
+
+fn octal(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("\\{:03o}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::params::Params;
+
+    fn quoted(args: &[&str], name: &str) -> String {
+        let params = Params::from_args(args.iter().map(|arg| arg.to_string())).unwrap();
+        quote(OsStr::new(name), &params.opts)
+    }
+
+    #[test]
+    fn shell_quoting() {
+        let shell = ["--quoting-style=shell", "--show-control-chars"];
+        assert_eq!(quoted(&shell, "plain.txt"), "plain.txt");
+        assert_eq!(quoted(&shell, "a b"), "'a b'");
+        assert_eq!(quoted(&shell, ""), "''");
+        assert_eq!(quoted(&shell, "#x"), "'#x'");
+        assert_eq!(quoted(&shell, "x#~"), "x#~");
+        assert_eq!(quoted(&shell, "it's"), "\"it's\"");
+        assert_eq!(quoted(&shell, "it's $5"), "'it'\\''s $5'");
+        assert_eq!(quoted(&["--quoting-style=shell-always"], "plain"), "'plain'");
+        assert_eq!(quoted(&["--quoting-style=shell", "-q"], "a\nb"), "'a?b'");
+    }
+
+    #[test]
+    fn shell_escape_quoting() {
+        let shell_escape = ["--quoting-style=shell-escape"];
+        assert_eq!(quoted(&shell_escape, "a\nb"), "'a'$'\\n''b'");
+        assert_eq!(quoted(&shell_escape, "x\x01"), "'x'$'\\001'");
+        assert_eq!(quoted(&shell_escape, "plain"), "plain");
+        assert_eq!(quoted(&["--quoting-style=shell-escape-always"], "plain"), "'plain'");
+    }
+
+    #[test]
+    fn escape_and_c_quoting() {
+        assert_eq!(quoted(&["--quoting-style=escape"], "a b\t\\"), "a\\ b\\t\\\\");
+        assert_eq!(quoted(&["--quoting-style=escape"], "say \"hi\""), "say\\ \"hi\"");
+        assert_eq!(quoted(&["--quoting-style=c"], "say \"hi\""), "\"say \\\"hi\\\"\"");
+        assert_eq!(quoted(&["-Q"], "\x01\r"), "\"\\001\\r\"");
+    }
+
+    #[test]
+    fn literal_names() {
+        assert_eq!(quoted(&["--literal"], "a\x01b"), "a\x01b");
+        assert_eq!(quoted(&["--literal", "-q"], "a\x01b"), "a?b");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn invalid_utf8() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let params = Params::from_args(["-b".to_string()]).unwrap();
+        assert_eq!(quote(OsStr::from_bytes(b"a\xffb"), &params.opts), "a\\377b");
+        let params = Params::from_args(["--quoting-style=shell-escape".to_string()]).unwrap();
+        assert_eq!(quote(OsStr::from_bytes(b"a\xff"), &params.opts), "'a'$'\\377'");
+    }
+}
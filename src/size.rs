@@ -0,0 +1,159 @@
+use crate::error::KlsError;
+
+const UNITS: &str = "KMGTPEZY";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum BlockSize {
+    Human,
+    Si,
+    // Block size, unit suffix and whether digits are grouped in thousands
+    Scaled(u64, Option<String>, bool)
+}
+
+impl BlockSize {
+    pub fn parse(spec: &str) -> Result<Self, KlsError> {
+        match spec {
+            "human-readable" => return Ok(Self::Human),
+            "si" => return Ok(Self::Si),
+            _ => ()
+        }
+
+        let invalid = || KlsError::S(format!("Invalid block size: '{}'", spec));
+
+        // A leading quote groups the digits like GNU's --block-size="'1"
+        let (grouped, spec) = match spec.strip_prefix('\'') {
+            Some(rest) => (true, rest),
+            None => (false, spec)
+        };
+        if spec.is_empty() {
+            return Err(invalid());
+        }
+
+        let digits = spec.chars().take_while(|c| c.is_ascii_digit()).count();
+        let (num, unit) = spec.split_at(digits);
+
+        let count = if num.is_empty() { 1 } else { num.parse::<u64>().map_err(|_| invalid())? };
+        let factor = if unit.is_empty() { 1 } else { Self::unit_factor(unit).ok_or_else(invalid)? };
+        let size = count
+            .checked_mul(factor)
+            .filter(|s| *s != 0)
+            .ok_or_else(invalid)?;
+
+        // A bare unit like `M` means the unit is printed after each size, with
+        // SI kilo spelled `kB` like GNU ls
+        let suffix = match unit {
+            _ if !num.is_empty() => None,
+            "KB" => Some(String::from("kB")),
+            unit => Some(unit.to_string())
+        };
+
+        Ok(Self::Scaled(size, suffix, grouped))
+    }
+
+    fn unit_factor(unit: &str) -> Option<u64> {
+        let mut chars = unit.chars();
+        let power = UNITS.find(chars.next()?.to_ascii_uppercase())? as u32 + 1;
+        let base: u64 = match chars.as_str() {
+            "" | "iB" => 1024,
+            "B" => 1000,
+            _ => return None
+        };
+
+        base.checked_pow(power)
+    }
+
+    // Human sizes are ceiling rounded with one decimal below 10, like GNU ls
+    pub fn format(&self, bytes: u64) -> String {
+        match self {
+            Self::Human => human_size(bytes, 1024),
+            Self::Si => human_size(bytes, 1000),
+            Self::Scaled(size, suffix, grouped) => {
+                let count = bytes.div_ceil(*size).to_string();
+                let count = if *grouped { group_digits(&count, ",") } else { count };
+                format!("{}{}", count, suffix.as_deref().unwrap_or(""))
+            }
+        }
+    }
+}
+
+// Inserts `separator` between each group of three digits, `12345678` becomes `12,345,678`
+pub fn group_digits(digits: &str, separator: &str) -> String {
+    let mut result = String::new();
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            result += separator;
+        }
+        result.push(c);
+    }
+    result
+}
+
+fn human_size(bytes: u64, base: u64) -> String {
+    if bytes < base {
+        return bytes.to_string();
+    }
+
+    let mut power = 0;
+    let mut value = bytes as f64;
+    while value >= base as f64 && power < UNITS.len() {
+        value /= base as f64;
+        power += 1;
+    }
+
+    let tenths = (value * 10.0).ceil() / 10.0;
+    let mut value = if tenths < 10.0 { tenths } else { value.ceil() };
+    if value >= base as f64 && power < UNITS.len() {
+        value = 1.0;
+        power += 1;
+    }
+
+    // SI kilo is a lowercase k
+    let unit = match UNITS.as_bytes()[power - 1] {
+        b'K' if base == 1000 => 'k',
+        c => c as char
+    };
+
+    if value < 10.0 {
+        format!("{:.1}{}", value, unit)
+    } else {
+        format!("{}{}", value, unit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_sizes() {
+        assert_eq!(BlockSize::parse("1024").unwrap(), BlockSize::Scaled(1024, None, false));
+        assert_eq!(BlockSize::parse("4K").unwrap(), BlockSize::Scaled(4096, None, false));
+        assert_eq!(BlockSize::parse("2KiB").unwrap(), BlockSize::Scaled(2048, None, false));
+        assert_eq!(BlockSize::parse("3kB").unwrap(), BlockSize::Scaled(3000, None, false));
+        assert_eq!(BlockSize::parse("human-readable").unwrap(), BlockSize::Human);
+        assert_eq!(BlockSize::parse("si").unwrap(), BlockSize::Si);
+    }
+
+    #[test]
+    fn parse_bare_units_and_grouping() {
+        assert_eq!(BlockSize::parse("M").unwrap(), BlockSize::Scaled(1 << 20, Some(String::from("M")), false));
+        assert_eq!(BlockSize::parse("KB").unwrap(), BlockSize::Scaled(1000, Some(String::from("kB")), false));
+        assert_eq!(BlockSize::parse("'1").unwrap(), BlockSize::Scaled(1, None, true));
+    }
+
+    #[test]
+    fn parse_invalid_sizes() {
+        for spec in ["", "0", "'", "4X", "4KQ", "-1", "99999999999999999999", "20Y"] {
+            assert!(BlockSize::parse(spec).is_err(), "{:?} should be rejected", spec);
+        }
+    }
+
+    #[test]
+    fn format_sizes() {
+        assert_eq!(BlockSize::Human.format(1023), "1023");
+        assert_eq!(BlockSize::Human.format(1025), "1.1K");
+        assert_eq!(BlockSize::Si.format(1000), "1.0k");
+        assert_eq!(BlockSize::Scaled(1024, None, false).format(1), "1");
+        assert_eq!(BlockSize::Scaled(1, None, true).format(1234567), "1,234,567");
+    }
+}
@@ -1,4 +1,4 @@
-use std::{fs, path::PathBuf};
+use std::{fs, ops::Range, path::{Path, PathBuf}};
 
 use crate::error::KlsError;
 
@@ -16,6 +16,7 @@ pub trait StrUtil {
 #[allow(unused)]
 pub trait PathUtil {
     fn kstr(&self) -> String;
+    fn klong(&self) -> Self where Self: Sized;
     fn canonicalize_relative_to(&self, base: &Self) -> Result<Self, KlsError> where Self: Sized;
     fn kabsolute(&self) -> Result<Self, KlsError> where Self: Sized;
 }
@@ -30,10 +31,10 @@ impl StrUtil for String {
     }
 
     fn repeat_to(&self, max_len: usize) -> Self {
-        if self.len() == 0 {
+        if self.is_empty() {
             self.clone()
         } else {
-            self.repeat(max_len / self.len() + (max_len % self.len() != 0) as usize).substr(0, max_len)
+            self.repeat(max_len / self.len() + !max_len.is_multiple_of(self.len()) as usize).substr(0, max_len)
         }
     }
 
@@ -72,19 +73,50 @@ impl StrUtil for String {
 
 impl PathUtil for PathBuf {
     fn kstr(&self) -> String {
-        self.as_os_str().to_str().unwrap_or("invalid-path").to_string()
+        let s = self.as_os_str().to_str().unwrap_or("invalid-path");
+
+        // Extended-length prefixes are an implementation detail, don't show them
+        #[cfg(windows)]
+        {
+            if let Some(share) = s.strip_prefix(r"\\?\UNC\") {
+                return format!(r"\\{}", share);
+            } else if let Some(rest) = s.strip_prefix(r"\\?\") {
+                return rest.to_string();
+            }
+        }
+
+        s.to_string()
+    }
+
+    // On Windows, turns the path into its `\\?\` extended-length form so it
+    // can exceed MAX_PATH. Elsewhere the path is returned as is.
+    fn klong(&self) -> PathBuf {
+        #[cfg(windows)]
+        {
+            if let Some(abs) = std::path::absolute(self).ok().as_ref().and_then(|p| p.to_str()) {
+                if abs.starts_with(r"\\?\") || abs.starts_with(r"\\.\") {
+                    return PathBuf::from(abs);
+                } else if let Some(share) = abs.strip_prefix(r"\\") {
+                    return PathBuf::from(format!(r"\\?\UNC\{}", share));
+                } else {
+                    return PathBuf::from(format!(r"\\?\{}", abs));
+                }
+            }
+        }
+
+        self.to_owned()
     }
 
     fn canonicalize_relative_to(&self, base: &PathBuf) -> Result<PathBuf, KlsError> {
         const ERR_CAN: &str = "Failed to canonicalize path";
         
         let path = if self.is_relative() {
-            base.join(&self)
+            base.join(self)
         } else {
             PathBuf::from(&self)
         };
 
-        Ok(fs::canonicalize(path).map_err(|e| KlsError::E(ERR_CAN.to_string(), Box::new(e)))?)
+        fs::canonicalize(path).map_err(|e| KlsError::E(ERR_CAN.to_string(), Box::new(e)))
     }
 
     fn kabsolute(&self) -> Result<Self, KlsError> {
@@ -96,9 +128,12 @@ impl PathUtil for PathBuf {
         if ! self.is_symlink() {
             Ok(self.canonicalize().map_err(|e| KlsError::E(ERR_CAN.to_string(), Box::new(e)))?)
         } else {
+            // A bare name like `link` has an empty parent, meaning the current directory
             let sym_dir = self
                 .parent()
-                .ok_or_else(|| KlsError::S(ERR_PARENT.to_string()))?
+                .ok_or_else(|| KlsError::S(ERR_PARENT.to_string()))?;
+            let sym_dir = if sym_dir.as_os_str().is_empty() { Path::new(".") } else { sym_dir };
+            let sym_dir = sym_dir
                 .canonicalize()
                 .map_err(|e| KlsError::E(ERR_CAN.to_string(), Box::new(e)))?;
 
@@ -111,3 +146,199 @@ impl PathUtil for PathBuf {
     }
 }
 
+
+// Shell style glob matching supporting `*`, `?`, `[...]` classes and `\` escapes
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    glob_match_spans(pattern, text).is_some()
+}
+
+// Like `glob_match`, but on a match returns the byte ranges of `text` matched by the
+// literal parts of the pattern (characters, escapes and classes, not `*` or `?`)
+pub fn glob_match_spans(pattern: &str, text: &str) -> Option<Vec<Range<usize>>> {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<(usize, char)> = text.char_indices().collect();
+
+    let (mut p, mut t) = (0, 0);
+    // Indices into `text` of the literally matched characters
+    let mut hits: Vec<usize> = vec![];
+    let mut backtrack: Option<(usize, usize, usize)> = None;
+
+    while t < text.len() {
+        let c = text[t].1;
+        let step = match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t, hits.len()));
+                p += 1;
+                continue;
+            },
+            Some('?') => Some((1, false)),
+            Some('[') => match_class(&pattern[p..], c).map(|len| (len, true)),
+            Some('\\') if p + 1 < pattern.len() => {
+                if pattern[p + 1] == c { Some((2, true)) } else { None }
+            },
+            Some(pc) => if *pc == c { Some((1, true)) } else { None },
+            None => None
+        };
+
+        match step {
+            Some((len, literal)) => {
+                if literal {
+                    hits.push(t);
+                }
+                p += len;
+                t += 1;
+            },
+            None => match backtrack {
+                Some((bp, bt, hit_count)) => {
+                    backtrack = Some((bp, bt + 1, hit_count));
+                    hits.truncate(hit_count);
+                    p = bp + 1;
+                    t = bt + 1;
+                },
+                None => return None
+            }
+        }
+    }
+
+    if !pattern[p..].iter().all(|c| *c == '*') {
+        return None;
+    }
+
+    // Merge runs of neighbouring characters into byte ranges
+    let end_of = |t: usize| text[t].0 + text[t].1.len_utf8();
+    let mut spans: Vec<Range<usize>> = vec![];
+    for t in hits {
+        match spans.last_mut() {
+            Some(span) if span.end == text[t].0 => span.end = end_of(t),
+            _ => spans.push(text[t].0..end_of(t))
+        }
+    }
+
+    Some(spans)
+}
+
+// Returns the length of the class at the start of `pattern` if it matches `c`
+fn match_class(pattern: &[char], c: char) -> Option<usize> {
+    let mut i = 1;
+    let negate = matches!(pattern.get(i), Some('!') | Some('^'));
+    if negate {
+        i += 1;
+    }
+
+    let mut found = false;
+    let mut first = true;
+    loop {
+        let start = *pattern.get(i)?;
+        if start == ']' && !first {
+            break;
+        }
+        first = false;
+
+        if pattern.get(i + 1) == Some(&'-') && pattern.get(i + 2).is_some_and(|e| *e != ']') {
+            if (start..=pattern[i + 2]).contains(&c) {
+                found = true;
+            }
+            i += 3;
+        } else {
+            if start == c {
+                found = true;
+            }
+            i += 1;
+        }
+    }
+
+    if found != negate { Some(i + 1) } else { None }
+}
+
+// $COLUMNS, then the columns of the terminal on stdout, then 80
+pub fn terminal_width() -> usize {
+    if let Some(columns) = parse_columns(std::env::var("COLUMNS").ok().as_deref()) {
+        return columns;
+    }
+
+    #[cfg(unix)]
+    {
+        use nix::libc;
+
+        let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+        if unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } == 0 && size.ws_col > 0 {
+            return size.ws_col as usize;
+        }
+    }
+
+    80
+}
+
+// $COLUMNS counts only when it's a positive number
+fn parse_columns(value: Option<&str>) -> Option<usize> {
+    value?.parse::<usize>().ok().filter(|columns| *columns > 0)
+}
+
+// Terminal columns taken by the text: wide east asian characters and emoji take
+// two, combining marks, other zero width characters and color codes none
+pub fn display_width(text: &str) -> usize {
+    let mut width = 0;
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' && chars.clone().next() == Some('[') {
+            // Up to the final byte of the escape sequence
+            chars.find(|c| ('@'..='~').contains(c) && *c != '[');
+        } else {
+            width += char_width(c);
+        }
+    }
+    width
+}
+
+fn char_width(c: char) -> usize {
+    const ZERO: [(u32, u32); 10] = [
+        (0x0300, 0x036f), (0x0483, 0x0489), (0x0591, 0x05bd), (0x1ab0, 0x1aff), (0x1dc0, 0x1dff),
+        (0x200b, 0x200f), (0x20d0, 0x20ff), (0xfe00, 0xfe0f), (0xfe20, 0xfe2f), (0xe0100, 0xe01ef)
+    ];
+    const WIDE: [(u32, u32); 17] = [
+        (0x1100, 0x115f), (0x2e80, 0x303e), (0x3041, 0x33ff), (0x3400, 0x4dbf), (0x4e00, 0x9fff),
+        (0xa000, 0xa4cf), (0xac00, 0xd7a3), (0xf900, 0xfaff), (0xfe30, 0xfe4f), (0xff00, 0xff60),
+        (0xffe0, 0xffe6), (0x1f300, 0x1f64f), (0x1f680, 0x1f6ff), (0x1f900, 0x1f9ff), (0x1fa70, 0x1faff),
+        (0x20000, 0x2fffd), (0x30000, 0x3fffd)
+    ];
+
+    let c = c as u32;
+    let within = |ranges: &[(u32, u32)]| ranges.iter().any(|(start, end)| (*start..=*end).contains(&c));
+    if c < 0x20 || (0x7f..0xa0).contains(&c) || within(&ZERO) {
+        0
+    } else if within(&WIDE) {
+        2
+    } else {
+        1
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::single_range_in_vec_init)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_spans_cover_literal_parts() {
+        assert_eq!(glob_match_spans("*.rs", "main.rs"), Some(vec![4..7]));
+        assert_eq!(glob_match_spans("f*s.rs", "files.rs"), Some(vec![0..1, 4..8]));
+        assert_eq!(glob_match_spans("?[a-c]t", "cat"), Some(vec![1..3]));
+        assert_eq!(glob_match_spans("*.rs", "main.rc"), None);
+    }
+
+    #[test]
+    fn columns_from_the_environment() {
+        assert_eq!(parse_columns(Some("40")), Some(40));
+        assert_eq!(parse_columns(Some("0")), None);
+        assert_eq!(parse_columns(Some("wide")), None);
+        assert_eq!(parse_columns(Some("")), None);
+        assert_eq!(parse_columns(None), None);
+    }
+
+    #[test]
+    fn glob_spans_are_byte_ranges() {
+        assert_eq!(glob_match_spans("*ö*", "zöz"), Some(vec![1..3]));
+        assert!(glob_match("a*b*c", "aXbYbZc"));
+        assert_eq!(glob_match_spans("a*bc", "abxbc"), Some(vec![0..1, 3..5]));
+    }
+}
@@ -0,0 +1,129 @@
+use std::path::Path;
+
+// Extended attributes through the libc re-exported by nix. `follow` picks
+// between the plain calls and their no-follow variants for symlinks.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+mod sys {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    use nix::libc;
+
+    fn c_path(path: &Path) -> Option<CString> {
+        CString::new(path.as_os_str().as_bytes()).ok()
+    }
+
+    #[cfg(target_os = "linux")]
+    fn list_raw(path: &CString, buf: &mut [u8], follow: bool) -> isize {
+        let (ptr, len) = (buf.as_mut_ptr() as *mut libc::c_char, buf.len());
+        unsafe {
+            if follow {
+                libc::listxattr(path.as_ptr(), ptr, len)
+            } else {
+                libc::llistxattr(path.as_ptr(), ptr, len)
+            }
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    fn list_raw(path: &CString, buf: &mut [u8], follow: bool) -> isize {
+        let options = if follow { 0 } else { libc::XATTR_NOFOLLOW };
+        unsafe { libc::listxattr(path.as_ptr(), buf.as_mut_ptr() as *mut libc::c_char, buf.len(), options) }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn get_raw(path: &CString, name: &CString, buf: &mut [u8], follow: bool) -> isize {
+        let (ptr, len) = (buf.as_mut_ptr() as *mut libc::c_void, buf.len());
+        unsafe {
+            if follow {
+                libc::getxattr(path.as_ptr(), name.as_ptr(), ptr, len)
+            } else {
+                libc::lgetxattr(path.as_ptr(), name.as_ptr(), ptr, len)
+            }
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    fn get_raw(path: &CString, name: &CString, buf: &mut [u8], follow: bool) -> isize {
+        let options = if follow { 0 } else { libc::XATTR_NOFOLLOW };
+        unsafe { libc::getxattr(path.as_ptr(), name.as_ptr(), buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0, options) }
+    }
+
+    // Asks for the size first, retrying if the value grew in between
+    fn read_sized(mut read: impl FnMut(&mut [u8]) -> isize) -> Option<Vec<u8>> {
+        loop {
+            let size = read(&mut []);
+            if size < 0 {
+                return None;
+            }
+
+            let mut buf = vec![0; size as usize];
+            let read_size = read(&mut buf);
+            if read_size >= 0 {
+                buf.truncate(read_size as usize);
+                return Some(buf);
+            }
+            if nix::errno::Errno::last() != nix::errno::Errno::ERANGE {
+                return None;
+            }
+        }
+    }
+
+    pub fn list(path: &Path, follow: bool) -> Vec<String> {
+        let Some(path) = c_path(path) else {
+            return vec![];
+        };
+
+        read_sized(|buf| list_raw(&path, buf, follow))
+            .unwrap_or_default()
+            .split(|c| *c == 0)
+            .filter(|name| !name.is_empty())
+            .map(|name| String::from_utf8_lossy(name).into_owned())
+            .collect()
+    }
+
+    pub fn get(path: &Path, name: &str, follow: bool) -> Option<Vec<u8>> {
+        let path = c_path(path)?;
+        let name = CString::new(name).ok()?;
+        read_sized(|buf| get_raw(&path, &name, buf, follow))
+    }
+
+    pub fn size(path: &Path, name: &str, follow: bool) -> Option<usize> {
+        let path = c_path(path)?;
+        let name = CString::new(name).ok()?;
+        usize::try_from(get_raw(&path, &name, &mut [], follow)).ok()
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+mod sys {
+    use std::path::Path;
+
+    pub fn list(_path: &Path, _follow: bool) -> Vec<String> {
+        vec![]
+    }
+
+    pub fn get(_path: &Path, _name: &str, _follow: bool) -> Option<Vec<u8>> {
+        None
+    }
+
+    pub fn size(_path: &Path, _name: &str, _follow: bool) -> Option<usize> {
+        None
+    }
+}
+
+// Attribute names, empty when there are none or they can't be read
+pub fn list(path: &Path, follow: bool) -> Vec<String> {
+    sys::list(path, follow)
+}
+
+// The value of a single attribute, None when it is missing
+pub fn get(path: &Path, name: &str, follow: bool) -> Option<Vec<u8>> {
+    sys::get(path, name, follow)
+}
+
+// The length of a value without reading it
+pub fn size(path: &Path, name: &str, follow: bool) -> Option<usize> {
+    sys::size(path, name, follow)
+}